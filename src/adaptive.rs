@@ -0,0 +1,219 @@
+// 区間の様子(カーブ、勾配、下りの速度、山頂)に合わせて再生速度を変えます
+// 見どころでは遅く(min_factor 倍)、まっすぐで平らな道では速く(max_factor 倍)再生します
+use crate::track_point::{self, TrackLog, TrackPoint};
+use chrono::{DateTime, Duration, Utc};
+
+// カーブを調べる前後の距離(m)
+const TURN_DISTANCE: f64 = 30.0;
+// この角度(度)以上曲がると最も遅くします
+const TURN_ANGLE: f64 = 90.0;
+// 勾配を調べる前後の距離(m)
+const GRADE_DISTANCE: f64 = 100.0;
+// この勾配以上で最も遅くします
+const GRADE: f64 = 0.08;
+// 下りでこの速度(km/h)以上なら最も遅くします
+const DESCENT_SPEED: f64 = 60.0;
+// 山頂を探す前後の距離(m)と、山頂とみなす高低差(m)
+const SUMMIT_DISTANCE: f64 = 500.0;
+const SUMMIT_PROMINENCE: f64 = 20.0;
+// 山頂の前後で遅くする距離(m)
+const SUMMIT_RANGE: f64 = 100.0;
+
+#[derive(Debug, Clone, Copy)]
+pub struct AdaptiveConfig {
+    // 最も遅くするときの倍率
+    pub min_factor: f64,
+    // 最も速くするときの倍率
+    pub max_factor: f64,
+    // 倍率の変化をならす時間
+    pub ease: Duration,
+}
+
+// 時刻ごとの再生速度の倍率
+#[derive(Debug, Clone)]
+pub struct SpeedProfile {
+    times: Vec<DateTime<Utc>>,
+    factors: Vec<f64>,
+}
+
+impl SpeedProfile {
+    pub fn new(track: &TrackLog, config: &AdaptiveConfig) -> Self {
+        let mut times = Vec::new();
+        let mut factors = Vec::new();
+
+        for segment in &track.segments {
+            let raw: Vec<f64> = interest(segment)
+                .iter()
+                .map(|x| config.max_factor - (config.max_factor - config.min_factor) * x)
+                .collect();
+
+            times.extend(segment.iter().map(|point| point.time));
+            factors.extend(ease(segment, &raw, config.ease));
+        }
+
+        Self { times, factors }
+    }
+
+    // 指定時刻の倍率(ポイントの間は補間します)
+    pub fn factor_at(&self, time: DateTime<Utc>) -> f64 {
+        if self.times.is_empty() {
+            return 1.0;
+        }
+
+        let index = self.times.partition_point(|t| *t < time);
+        if index == 0 {
+            return self.factors[0];
+        }
+        if index >= self.times.len() {
+            return self.factors[self.factors.len() - 1];
+        }
+
+        let (prev, next) = (self.times[index - 1], self.times[index]);
+        let span = (next - prev).num_milliseconds() as f64;
+        if span <= 0.0 {
+            return self.factors[index];
+        }
+        let ratio = (time - prev).num_milliseconds() as f64 / span;
+        self.factors[index - 1] + (self.factors[index] - self.factors[index - 1]) * ratio
+    }
+}
+
+// ポイントごとの見どころの度合い(0.0〜1.0)
+fn interest(points: &[TrackPoint]) -> Vec<f64> {
+    let mut distances = vec![0.0];
+    for pair in points.windows(2) {
+        distances.push(distances[distances.len() - 1] + track_point::distance(&pair[0], &pair[1]));
+    }
+
+    // 前後 distance m の位置(範囲外の場合は端)
+    let before = |index: usize, distance: f64| {
+        let target = distances[index] - distance;
+        distances.partition_point(|d| *d <= target).saturating_sub(1).min(index)
+    };
+    let after = |index: usize, distance: f64| {
+        let target = distances[index] + distance;
+        distances.partition_point(|d| *d < target).clamp(index, points.len() - 1)
+    };
+
+    let mut result = vec![0.0; points.len()];
+    let mut summits = Vec::new();
+
+    for (index, point) in points.iter().enumerate() {
+        // カーブ
+        let (from, to) = (before(index, TURN_DISTANCE), after(index, TURN_DISTANCE));
+        if from < index && index < to {
+            let mut turn = (bearing(&points[from], point) - bearing(point, &points[to])).abs();
+            if turn > 180.0 {
+                turn = 360.0 - turn;
+            }
+            result[index] = (turn / TURN_ANGLE).min(1.0);
+        }
+
+        // 勾配と下りの速度
+        let (from, to) = (before(index, GRADE_DISTANCE), after(index, GRADE_DISTANCE));
+        let length = distances[to] - distances[from];
+        if let (Some(ele_from), Some(ele_to)) = (points[from].ele, points[to].ele) {
+            if length >= GRADE_DISTANCE {
+                let grade = (ele_to - ele_from) / length;
+                result[index] = f64::max(result[index], (grade.abs() / GRADE).min(1.0));
+
+                let seconds = (points[to].time - points[from].time).num_milliseconds() as f64 / 1000.0;
+                if grade < -0.02 && seconds > 0.0 {
+                    let speed = length / seconds * 3.6;
+                    result[index] = f64::max(result[index], (speed / DESCENT_SPEED).min(1.0));
+                }
+            }
+        }
+
+        // 山頂(前後の範囲で最も高く、十分な高低差がある)
+        if let Some(ele) = point.ele {
+            let window = &points[before(index, SUMMIT_DISTANCE)..=after(index, SUMMIT_DISTANCE)];
+            let values = window.iter().filter_map(|p| p.ele);
+            let (max, min) = values.fold((f64::MIN, f64::MAX), |(max, min), x| (max.max(x), min.min(x)));
+            if ele >= max && ele - min >= SUMMIT_PROMINENCE {
+                summits.push(distances[index]);
+            }
+        }
+    }
+
+    for (index, distance) in distances.iter().enumerate() {
+        if summits.iter().any(|summit| (summit - distance).abs() <= SUMMIT_RANGE) {
+            result[index] = 1.0;
+        }
+    }
+
+    result
+}
+
+// 方位(度、北が0で時計回り)
+fn bearing(a: &TrackPoint, b: &TrackPoint) -> f64 {
+    let (lat1, lat2) = (a.lat.to_radians(), b.lat.to_radians());
+    let dlng = (b.lng - a.lng).to_radians();
+
+    let y = dlng.sin() * lat2.cos();
+    let x = lat1.cos() * lat2.sin() - lat1.sin() * lat2.cos() * dlng.cos();
+    y.atan2(x).to_degrees().rem_euclid(360.0)
+}
+
+// 倍率が急に変わらないように前後から指数移動平均をかけます
+fn ease(points: &[TrackPoint], values: &[f64], ease: Duration) -> Vec<f64> {
+    let seconds = ease.num_milliseconds() as f64 / 1000.0;
+    if seconds <= 0.0 || values.is_empty() {
+        return values.to_vec();
+    }
+
+    let smooth = |indexes: &mut dyn Iterator<Item = usize>| {
+        let mut result = values.to_vec();
+        let mut prev: Option<usize> = None;
+        for index in indexes {
+            if let Some(prev) = prev {
+                let dt = (points[index].time - points[prev].time).num_milliseconds().abs() as f64 / 1000.0;
+                let alpha = 1.0 - (-dt / seconds).exp();
+                result[index] = result[prev] + (values[index] - result[prev]) * alpha;
+            }
+            prev = Some(index);
+        }
+        result
+    };
+
+    let forward = smooth(&mut (0..values.len()));
+    let backward = smooth(&mut (0..values.len()).rev());
+
+    // 遅くする方を優先して、見どころの手前から減速します
+    forward.iter().zip(&backward).map(|(f, b)| f.min(*b)).collect()
+}
+
+#[test]
+fn adaptive_turn() {
+    use crate::track_point::{test_point, test_time, test_track};
+    use crate::track_point::{Playback, TrackIter};
+
+    // 北へ500m進んで直角に曲がり、東へ500m進む(1秒ごとに約10m)
+    let mut points: Vec<TrackPoint> = (0..=50)
+        .map(|i| test_point(i, 35.0 + i as f64 * 0.00009))
+        .collect();
+    points.extend((1..=50).map(|i| TrackPoint::new(test_time(50 + i), 35.0045, 139.5 + i as f64 * 0.00011)));
+    let track = test_track(points);
+
+    let config = AdaptiveConfig {
+        min_factor: 0.5,
+        max_factor: 3.0,
+        ease: Duration::zero(),
+    };
+    let profile = SpeedProfile::new(&track, &config);
+    assert!((profile.factor_at(test_time(50)) - 0.5).abs() < 0.1);
+    assert_eq!(profile.factor_at(test_time(20)), 3.0);
+
+    // ならすと角の手前から徐々に遅くなる
+    let profile = SpeedProfile::new(&track, &AdaptiveConfig { ease: Duration::seconds(5), ..config });
+    let (straight, before, corner) = (profile.factor_at(test_time(20)), profile.factor_at(test_time(45)), profile.factor_at(test_time(50)));
+    assert!(corner < before && before < straight, "{} {} {}", straight, before, corner);
+
+    // 長さを指定した場合は、倍率を含めて10秒(30fpsで300フレーム)に収める
+    let iter = TrackIter::get_iter(&track, 30, None, None)
+        .with_speed_profile(profile)
+        .with_playback(Playback::Duration(Duration::seconds(10)));
+    assert!((iter.video_length() - 10.0).abs() < 0.1, "{}", iter.video_length());
+    let count = iter.count();
+    assert!((299..=302).contains(&count), "{}", count);
+}
@@ -1,21 +1,106 @@
-use chrono::{DateTime, Local, NaiveDateTime, TimeZone, Utc};
+use crate::{
+    adaptive::AdaptiveConfig,
+    cleaning::CleanConfig,
+    elevation::ElevationFilter,
+    laps::{LapKind, LapMode},
+    loader::{self, TrackSelection},
+    overlay::GapIndicator,
+    privacy::{Privacy, PrivacyStyle, PrivacyZone},
+    segments::Segment,
+    smoothing::Smoothing,
+    stats::StatsConfig,
+    stops::StopMode,
+    synthesis::{SpeedModel, TimeSynthesis},
+    track_point::{GapMode, Playback, TimeOrder, TrackLog},
+    trim::{Area, TimeRange, Trim, TrimBound},
+};
+use anyhow::Result;
+use chrono::{DateTime, Duration, FixedOffset, Local, NaiveDateTime, NaiveTime, Offset, TimeZone, Utc};
+use chrono_tz::Tz;
 use clap::Clap;
 
+const DEFAULT_DEST_FILE: &str = "dest.mp4";
+// 距離基準で速度の指定がない場合の、動画1秒あたりの距離(m)
+const DEFAULT_DISTANCE_SPEED: f64 = 100.0;
+
 #[derive(Clap)]
 #[clap(version = "0.1", author = "Yoshiyuki Saito")]
 pub struct Opts {
-    #[clap(about = "処理対象のgpxファイル")]
-    pub gpx_file: String,
+    #[clap(subcommand)]
+    pub command: Option<SubCommand>,
 
-    #[clap(about = "出力するmp4ファイル", default_value = "dest.mp4")]
-    pub dest_file: String,
+    #[clap(
+        about = "処理対象のgpx/igc/srtファイル(複数可、ディレクトリや*?も指定可)と、最後に出力するmp4ファイル(省略時はdest.mp4)"
+    )]
+    pub files: Vec<String>,
 
-    #[clap(short, long, about = "処理対象日時（開始）- %Y-%m-%d %H:%M:%S")]
+    #[clap(
+        short,
+        long,
+        global = true,
+        about = "処理対象日時（開始）- %Y-%m-%d %H:%M:%S、RFC3339、時刻のみ(%H:%M)、トラック開始からの経過時間(+15m)"
+    )]
     pub start_dt: Option<String>,
 
-    #[clap(short, long, about = "処理対象日時（終了）- %Y-%m-%d %H:%M:%S")]
+    #[clap(short, long, global = true, about = "処理対象日時（終了）- start-dt と同じ形式")]
     pub end_dt: Option<String>,
 
+    #[clap(long, global = true, about = "処理対象の開始からの長さ(30m、1h30m など)", conflicts_with = "end-dt")]
+    pub window: Option<String>,
+
+    #[clap(long, global = true, about = "トラックの開始からこの距離(km)の地点から処理する")]
+    pub from_km: Option<f64>,
+
+    #[clap(long, global = true, about = "トラックの開始からこの距離(km)の地点まで処理する")]
+    pub to_km: Option<f64>,
+
+    #[clap(long, global = true, about = "この名前のウェイポイントに最も近づいた地点から処理する", conflicts_with = "from-km")]
+    pub from_waypoint: Option<String>,
+
+    #[clap(long, global = true, about = "この名前のウェイポイントに最も近づいた地点まで処理する", conflicts_with = "to-km")]
+    pub to_waypoint: Option<String>,
+
+    #[clap(
+        long,
+        global = true,
+        about = "この座標(緯度,経度)に最も近づいた地点から処理する",
+        conflicts_with_all = &["from-km", "from-waypoint"]
+    )]
+    pub from_point: Option<String>,
+
+    #[clap(
+        long,
+        global = true,
+        about = "この座標(緯度,経度)に最も近づいた地点まで処理する",
+        conflicts_with_all = &["to-km", "to-waypoint"]
+    )]
+    pub to_point: Option<String>,
+
+    #[clap(long, global = true, about = "この範囲に入ってから出るまでを処理する(南端の緯度,西端の経度,北端の緯度,東端の経度 またはGeoJSONファイル)")]
+    pub area: Option<String>,
+
+    #[clap(long, global = true, about = "ルートを隠すプライバシーゾーン(緯度,経度,半径(m)、複数回指定可)", number_of_values = 1)]
+    pub privacy_zone: Vec<String>,
+
+    #[clap(
+        long,
+        global = true,
+        about = "ルートを隠すプライバシーゾーン(南端の緯度,西端の経度,北端の緯度,東端の経度 またはGeoJSONファイル、複数回指定可)",
+        number_of_values = 1
+    )]
+    pub privacy_area: Vec<String>,
+
+    #[clap(
+        long,
+        about = "プライバシーゾーン内の地図の隠し方(blur: ぼかす、desaturate: 色を抜く)",
+        default_value = "blur",
+        possible_values = &["blur", "desaturate"]
+    )]
+    pub privacy_style: PrivacyStyle,
+
+    #[clap(long, global = true, about = "日時の入力と表示のタイムゾーン(Asia/Tokyo など、省略時はこのコンピュータのタイムゾーン)")]
+    pub tz: Option<String>,
+
     #[clap(short, long, about = "動画の一辺の長さ", default_value = "400")]
     pub map_image_size: u32,
 
@@ -29,27 +114,639 @@ pub struct Opts {
 
     #[clap(short, long, about = "マップタイル保存ディレクトリ", default_value = "tiles")]
     pub tile_dir: String,
+
+    #[clap(
+        long,
+        global = true,
+        about = "描画するトラック(番号または名前)",
+        conflicts_with = "all-tracks"
+    )]
+    pub track: Option<String>,
+
+    #[clap(long, global = true, about = "全トラックを時刻順に連結して描画する")]
+    pub all_tracks: bool,
+
+    #[clap(
+        long,
+        about = "トラックごとに動画を出力する",
+        conflicts_with_all = &["track", "all-tracks"]
+    )]
+    pub each_track: bool,
+
+    #[clap(
+        long,
+        about = "ギャップ(セグメントの切れ目、信号途絶)の扱い(skip: 飛ばす、jump: その場に留まる、interpolate: 従来どおり補間する)",
+        default_value = "interpolate",
+        possible_values = &["skip", "jump", "interpolate"]
+    )]
+    pub gap_mode: GapMode,
+
+    #[clap(
+        long,
+        about = "skip、jump でこの秒数より間隔が空いたらギャップとみなす(0で無効)",
+        default_value = "60"
+    )]
+    pub max_gap: i64,
+
+    #[clap(
+        long,
+        global = true,
+        about = "時刻が逆行しているポイントの扱い(sort: 並べ替える、drop: 捨てる、error: エラーにする)",
+        default_value = "sort",
+        possible_values = &["sort", "drop", "error"]
+    )]
+    pub time_order: TimeOrder,
+
+    #[clap(
+        long,
+        about = "jump の場合の信号途絶中の表示",
+        default_value = "text",
+        possible_values = &["text", "fade", "none"]
+    )]
+    pub gap_indicator: GapIndicator,
+
+    #[clap(
+        long,
+        global = true,
+        about = "標高の平滑化フィルタ",
+        default_value = "average",
+        possible_values = &["none", "average", "median"]
+    )]
+    pub ele_filter: ElevationFilter,
+
+    #[clap(long, global = true, about = "標高の平滑化に使う前後の秒数", default_value = "15")]
+    pub ele_window: i64,
+
+    #[clap(
+        long,
+        global = true,
+        about = "獲得標高に数える最小の標高変化(m)",
+        default_value = "5"
+    )]
+    pub ele_threshold: f64,
+
+    #[clap(long, about = "標高と獲得標高を表示する")]
+    pub show_elevation: bool,
+
+    #[clap(long, about = "登りを検出して、登り口にバナー、山頂にマーカーを表示する")]
+    pub climbs: bool,
+
+    #[clap(long, global = true, about = "標高を補正するDEM(SRTMの.hgt、地理院の標高タイル)のディレクトリ")]
+    pub dem: Option<String>,
+
+    #[clap(
+        long,
+        global = true,
+        about = "DEMの標高の比率(1.0でDEMの値に置き換え、0.5で記録された値と半々)",
+        default_value = "1.0"
+    )]
+    pub dem_weight: f64,
+
+    #[clap(
+        long,
+        about = "再生速度(60x で動画1秒がトラックの60秒、200m で動画1秒が200m、省略時は実時間)",
+        conflicts_with_all = &["realtime", "duration"]
+    )]
+    pub speed: Option<String>,
+
+    #[clap(long, about = "実時間で再生する", conflicts_with = "duration")]
+    pub realtime: bool,
+
+    #[clap(long, about = "対象範囲全体がこの長さの動画になるように再生速度を決める(90s, 2m, 1m30s など)")]
+    pub duration: Option<String>,
+
+    #[clap(long, about = "時間ではなく距離を基準に再生する(1kmごとに同じ長さになる)", conflicts_with = "realtime")]
+    pub by_distance: bool,
+
+    #[clap(
+        long,
+        about = "停止区間(休憩、信号待ち)の再生方法",
+        default_value = "none",
+        possible_values = &["none", "skip", "card", "fast"]
+    )]
+    pub stops: StopMode,
+
+    #[clap(long, global = true, about = "この速度(km/h)未満を停止とみなす", default_value = "2")]
+    pub stop_speed: f64,
+
+    #[clap(long, about = "この秒数以上続いたら停止区間とみなす", default_value = "60")]
+    pub stop_time: i64,
+
+    #[clap(long, about = "fast の場合の停止区間の早送りの倍率", default_value = "10")]
+    pub stop_speedup: f64,
+
+    #[clap(long, about = "カーブや急な坂、山頂では遅く、まっすぐで平らな道では速く再生する")]
+    pub adaptive: bool,
+
+    #[clap(long, about = "adaptive で最も遅くするときの再生速度の倍率", default_value = "0.5")]
+    pub adaptive_min: f64,
+
+    #[clap(long, about = "adaptive で最も速くするときの再生速度の倍率", default_value = "3")]
+    pub adaptive_max: f64,
+
+    #[clap(long, about = "adaptive で再生速度の変化をならす秒数", default_value = "10")]
+    pub adaptive_ease: i64,
+
+    #[clap(
+        long,
+        global = true,
+        about = "位置の揺れの平滑化(kalman: カルマンフィルタ、spline: スプライン補間)",
+        default_value = "none",
+        possible_values = &["none", "kalman", "spline"]
+    )]
+    pub smoothing: Smoothing,
+
+    #[clap(long, global = true, about = "kalman で想定するGPSの誤差(m)", default_value = "5")]
+    pub smoothing_noise: f64,
+
+    #[clap(long, global = true, about = "飛び値や重複したポイントを削除する")]
+    pub clean: bool,
+
+    #[clap(long, global = true, about = "clean でこれを超える速度(km/h)で飛んで戻るポイントを削除する", default_value = "150")]
+    pub max_speed: f64,
+
+    #[clap(long, global = true, about = "clean でこれを超える加速度(m/s^2)で飛んで戻るポイントを削除する", default_value = "10")]
+    pub max_accel: f64,
+
+    #[clap(long, global = true, about = "時刻のないトラック・ルート(計画ルートなど)に、この速度(km/h)で移動したとして時刻を付ける")]
+    pub synth_speed: Option<f64>,
+
+    #[clap(
+        long,
+        global = true,
+        about = "synth-speed の速度の付け方(constant: 一定、grade: 標高から上りは遅く下りは速く)",
+        default_value = "grade",
+        possible_values = &["constant", "grade"]
+    )]
+    pub synth_model: SpeedModel,
+
+    #[clap(long, about = "処理したトラックの出力先(拡張子で gpx, geojson, csv を判断します)")]
+    pub export: Option<String>,
+
+    #[clap(long, about = "export でポイントの代わりにフレームごとの補間した位置を出力する", requires = "export")]
+    pub export_frames: bool,
+
+    #[clap(long, about = "動画を作らずに export の出力だけをする", requires = "export")]
+    pub export_only: bool,
+
+    #[clap(
+        long,
+        global = true,
+        about = "ラップの区切り方(segments: セグメント、markers: Lapで始まるウェイポイント、distance: lap-distanceごと、line: lap-lineの通過)",
+        default_value = "none",
+        possible_values = &["none", "segments", "markers", "distance", "line"]
+    )]
+    pub laps: LapKind,
+
+    #[clap(long, global = true, about = "laps distance でラップを区切る距離(km)", default_value = "1")]
+    pub lap_distance: f64,
+
+    #[clap(long, global = true, about = "laps line のスタートライン(緯度,経度,緯度,経度)")]
+    pub lap_line: Option<String>,
+
+    #[clap(long, global = true, about = "セグメントの定義(開始・終了のゲート、または経路と許容距離)のGeoJSONファイル")]
+    pub segments: Option<String>,
+
+    #[clap(long, global = true, about = "セグメントのタイムを比較するファイル(動画を作成したときに記録を追加します)", default_value = "segment_history.json")]
+    pub segment_history: String,
+}
+
+#[derive(Clap)]
+pub enum SubCommand {
+    #[clap(about = "ファイル内のトラック・セグメントの一覧を表示する")]
+    Info(InfoOpts),
+    #[clap(about = "トラックの問題(時刻の抜けや逆行、重複、ギャップ、座標の範囲など)を検査する")]
+    Lint(LintOpts),
+    #[clap(about = "走行の統計(距離、時間、速度、標高、心拍数・パワー、範囲、登り、セグメント)を表示する")]
+    Stats(StatsOpts),
+}
+
+#[derive(Clap)]
+pub struct InfoOpts {
+    #[clap(about = "処理対象のgpx/igc/srtファイル(複数可、ディレクトリや*?も指定可)", required = true)]
+    pub files: Vec<String>,
+}
+
+#[derive(Clap)]
+pub struct LintOpts {
+    #[clap(about = "処理対象のgpx/igc/srtファイル(複数可、ディレクトリや*?も指定可)", required = true)]
+    pub files: Vec<String>,
+
+    #[clap(long, about = "この秒数より間隔が空いていたら報告する", default_value = "60")]
+    pub max_gap: i64,
+
+    #[clap(long, about = "修正したgpxファイルの出力先(入力ファイルが1つの場合のみ)")]
+    pub fix: Option<String>,
+}
+
+#[derive(Clap)]
+pub struct StatsOpts {
+    #[clap(about = "処理対象のgpx/igc/srtファイル(複数可、ディレクトリや*?も指定可)", required = true)]
+    pub files: Vec<String>,
+
+    #[clap(long, about = "JSONで出力する")]
+    pub json: bool,
+
+    #[clap(long, about = "ラップごとの距離・時間・平均速度も表示する(区切り方は --laps で指定)")]
+    pub splits: bool,
 }
 
 impl Opts {
-    pub fn get_start_date(&self) -> Option<DateTime<Utc>> {
-        get_date_parameter(&self.start_dt)
+    pub fn get_ele_window(&self) -> Duration {
+        Duration::seconds(self.ele_window)
+    }
+
+    pub fn get_clean_config(&self) -> CleanConfig {
+        CleanConfig {
+            max_speed: self.max_speed,
+            max_acceleration: self.max_accel,
+        }
+    }
+
+    pub fn get_dem_weight(&self) -> Result<f64> {
+        if (0.0..=1.0).contains(&self.dem_weight) {
+            Ok(self.dem_weight)
+        } else {
+            Err(anyhow::anyhow!("DEMの標高の比率は 0.0 から 1.0 の範囲で指定してください: {}", self.dem_weight))
+        }
+    }
+
+    // 出発時刻は start-dt(時刻のみの場合は今日、経過時間の場合は指定なし)です
+    pub fn get_synthesis(&self) -> Result<Option<TimeSynthesis>> {
+        let speed = match self.synth_speed {
+            Some(speed) if speed > 0.0 => speed,
+            Some(speed) => return Err(anyhow::anyhow!("時刻を合成する速度は0より大きくしてください: {}", speed)),
+            None => return Ok(None),
+        };
+
+        let tz = self.get_timezone()?;
+        let start = match self.start_dt.as_deref().map(|x| parse_date_parameter(x, tz)).transpose()? {
+            Some(DateParameter::Offset(_)) | None => None,
+            Some(start) => Some(start.resolve(Utc::now(), tz)?),
+        };
+
+        Ok(Some(TimeSynthesis {
+            speed,
+            model: self.synth_model,
+            start,
+        }))
+    }
+
+    // 移動時間は停止とみなす速度、獲得標高は表示と同じしきい値で求めます
+    pub fn get_stats_config(&self) -> StatsConfig {
+        StatsConfig {
+            moving_speed: self.stop_speed,
+            ele_threshold: self.ele_threshold,
+        }
+    }
+
+    pub fn get_adaptive_config(&self) -> AdaptiveConfig {
+        AdaptiveConfig {
+            min_factor: self.adaptive_min,
+            max_factor: self.adaptive_max,
+            ease: Duration::seconds(self.adaptive_ease),
+        }
+    }
+
+    pub fn get_stop_time(&self) -> Duration {
+        Duration::seconds(self.stop_time)
+    }
+
+    pub fn get_max_gap(&self) -> Option<Duration> {
+        if self.max_gap > 0 {
+            Some(Duration::seconds(self.max_gap))
+        } else {
+            None
+        }
+    }
+
+    pub fn get_input_files(&self) -> Result<Vec<String>> {
+        let (inputs, _) = self.split_dest_file();
+        if inputs.is_empty() {
+            return Err(anyhow::anyhow!("処理対象のファイルを指定してください"));
+        }
+
+        loader::expand_paths(inputs)
+    }
+
+    pub fn get_dest_file(&self) -> String {
+        let (_, dest) = self.split_dest_file();
+        dest.cloned().unwrap_or_else(|| DEFAULT_DEST_FILE.to_string())
+    }
+
+    // 最後の引数がトラックのファイルでなければ出力ファイルとみなします
+    fn split_dest_file(&self) -> (&[String], Option<&String>) {
+        match self.files.split_last() {
+            Some((last, inputs)) if !inputs.is_empty() && !loader::is_track_path(last) => {
+                (inputs, Some(last))
+            }
+            _ => (&self.files, None),
+        }
+    }
+
+    // 複数ファイルの場合は、指定がなければ全トラックを連結します
+    pub fn get_track_selection(&self, file_count: usize) -> TrackSelection {
+        TrackSelection::parse(self.track.as_deref(), self.all_tracks || file_count > 1)
+    }
+
+    pub fn get_playback(&self) -> Result<Playback> {
+        if self.realtime {
+            return Ok(Playback::Speed(1.0));
+        }
+
+        if let Some(speed) = &self.speed {
+            return parse_speed(speed, self.by_distance);
+        }
+
+        if let Some(duration) = &self.duration {
+            let duration = parse_duration(duration)?;
+            return Ok(if self.by_distance {
+                Playback::DistanceDuration(duration)
+            } else {
+                Playback::Duration(duration)
+            });
+        }
+
+        Ok(if self.by_distance {
+            Playback::Distance(DEFAULT_DISTANCE_SPEED)
+        } else {
+            Playback::Speed(1.0)
+        })
+    }
+
+    pub fn get_trim(&self) -> Result<Trim> {
+        let bound = |km: Option<f64>, waypoint: &Option<String>, point: &Option<String>| -> Result<Option<TrimBound>> {
+            if let Some(km) = km {
+                return Ok(Some(TrimBound::Distance(km * 1000.0)));
+            }
+            if let Some(name) = waypoint {
+                return Ok(Some(TrimBound::Waypoint(name.clone())));
+            }
+            match point {
+                Some(point) => {
+                    let (lat, lng) = parse_lat_lng(point)?;
+                    Ok(Some(TrimBound::Nearest(lat, lng)))
+                }
+                None => Ok(None),
+            }
+        };
+
+        Ok(Trim {
+            from: bound(self.from_km, &self.from_waypoint, &self.from_point)?,
+            to: bound(self.to_km, &self.to_waypoint, &self.to_point)?,
+            area: self.area.as_deref().map(Area::parse).transpose()?,
+        })
+    }
+
+    pub fn get_privacy(&self) -> Result<Privacy> {
+        let circles = self.privacy_zone.iter().map(|zone| PrivacyZone::parse_circle(zone));
+        let areas = self.privacy_area.iter().map(|area| Area::parse(area).map(PrivacyZone::Area));
+
+        Ok(Privacy {
+            zones: circles.chain(areas).collect::<Result<_>>()?,
+            style: self.privacy_style,
+        })
+    }
+
+    pub fn get_lap_mode(&self) -> Result<LapMode> {
+        match self.laps {
+            LapKind::None => Ok(LapMode::None),
+            LapKind::Segments => Ok(LapMode::Segments),
+            LapKind::Markers => Ok(LapMode::Markers),
+            LapKind::Distance => Ok(LapMode::Distance(self.lap_distance * 1000.0)),
+            LapKind::Line => {
+                let line = self
+                    .lap_line
+                    .as_deref()
+                    .ok_or_else(|| anyhow::anyhow!("--laps line には --lap-line でスタートラインを指定してください"))?;
+                let values: Vec<Option<f64>> = line.split(',').map(|x| x.trim().parse().ok()).collect();
+                match values.as_slice() {
+                    [Some(lat1), Some(lng1), Some(lat2), Some(lng2)] => {
+                        Ok(LapMode::Line((*lat1, *lng1), (*lat2, *lng2)))
+                    }
+                    _ => Err(anyhow::anyhow!("スタートライン \"{}\" は 緯度,経度,緯度,経度 で指定してください", line)),
+                }
+            }
+        }
+    }
+
+    pub fn get_segments(&self) -> Result<Vec<Segment>> {
+        match &self.segments {
+            Some(path) => Segment::load(path),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    pub fn get_timezone(&self) -> Result<Option<Tz>> {
+        self.tz
+            .as_deref()
+            .map(|tz| tz.parse().map_err(|_| anyhow::anyhow!("タイムゾーン \"{}\" がみつかりません", tz)))
+            .transpose()
+    }
+
+    // 処理対象の開始・終了日時(時刻のみ、経過時間の指定はトラックの開始を基準にします)
+    pub fn get_time_range(&self, track: &TrackLog) -> Result<TimeRange> {
+        let tz = self.get_timezone()?;
+        let track_start = track.time_range().map_or_else(Utc::now, |(start, _)| start);
+        let resolve = |value: &Option<String>| -> Result<Option<DateTime<Utc>>> {
+            match value {
+                Some(value) => Ok(Some(parse_date_parameter(value, tz)?.resolve(track_start, tz)?)),
+                None => Ok(None),
+            }
+        };
+
+        // 日時と距離・位置、プライバシーゾーンの指定がある場合は、重なる範囲にします
+        let (trim_start, trim_end) = self.get_trim()?.time_range(track)?;
+        let (privacy_start, privacy_end) = self.get_privacy()?.time_range(track)?;
+        let start = resolve(&self.start_dt)?.max(trim_start).max(privacy_start);
+        let end = match &self.window {
+            Some(window) => Some(start.unwrap_or(track_start) + parse_duration(window)?),
+            None => resolve(&self.end_dt)?,
+        };
+        let end = [end, trim_end, privacy_end].iter().flatten().min().copied();
+
+        if let (Some(start), Some(end)) = (start, end) {
+            if start >= end {
+                return Err(anyhow::anyhow!("処理対象の終了日時が開始日時より前になっています"));
+            }
+        }
+        Ok((start, end))
+    }
+}
+
+// "35.6,139.2" のような緯度,経度
+fn parse_lat_lng(value: &str) -> Result<(f64, f64)> {
+    let values: Vec<Option<f64>> = value.split(',').map(|x| x.trim().parse().ok()).collect();
+    match values.as_slice() {
+        [Some(lat), Some(lng)] if (-90.0..=90.0).contains(lat) && (-180.0..=180.0).contains(lng) => Ok((*lat, *lng)),
+        _ => Err(anyhow::anyhow!("座標 \"{}\" は 緯度,経度 で指定してください", value)),
+    }
+}
+
+// --start-dt、--end-dt の値
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DateParameter {
+    DateTime(DateTime<Utc>),
+    // 時刻のみ(トラックの開始日の時刻)
+    Time(NaiveTime),
+    // トラックの開始からの経過時間
+    Offset(Duration),
+}
+
+impl DateParameter {
+    pub fn resolve(&self, track_start: DateTime<Utc>, tz: Option<Tz>) -> Result<DateTime<Utc>> {
+        match self {
+            DateParameter::DateTime(dt) => Ok(*dt),
+            DateParameter::Time(time) => {
+                let date = to_local(track_start, tz).naive_local().date();
+                from_local(&date.and_time(*time), tz)
+            }
+            DateParameter::Offset(offset) => Ok(track_start + *offset),
+        }
+    }
+}
+
+// タイムゾーンのない日時は tz(なければこのコンピュータのタイムゾーン)の日時とします
+pub fn parse_date_parameter(value: &str, tz: Option<Tz>) -> Result<DateParameter> {
+    let error = || {
+        anyhow::anyhow!(
+            "日時 \"{}\" の形式が正しくありません(例: 2020-08-01 09:00:00、2020-08-01T09:00:00+09:00、09:00、+15m)",
+            value
+        )
+    };
+
+    if let Some(offset) = value.strip_prefix('+') {
+        return parse_duration(offset).map(DateParameter::Offset).map_err(|_| error());
+    }
+    if let Ok(dt) = DateTime::parse_from_rfc3339(value) {
+        return Ok(DateParameter::DateTime(dt.with_timezone(&Utc)));
+    }
+    for format in &["%Y-%m-%d %H:%M:%S", "%Y-%m-%dT%H:%M:%S", "%Y-%m-%d %H:%M", "%Y/%m/%d %H:%M:%S", "%Y/%m/%d %H:%M"] {
+        if let Ok(dt) = NaiveDateTime::parse_from_str(value, format) {
+            return from_local(&dt, tz).map(DateParameter::DateTime);
+        }
     }
+    for format in &["%H:%M:%S", "%H:%M"] {
+        if let Ok(time) = NaiveTime::parse_from_str(value, format) {
+            return Ok(DateParameter::Time(time));
+        }
+    }
+
+    Err(error())
+}
+
+pub fn from_local(dt: &NaiveDateTime, tz: Option<Tz>) -> Result<DateTime<Utc>> {
+    let result = match tz {
+        Some(tz) => tz.from_local_datetime(dt).single().map(|x| x.with_timezone(&Utc)),
+        None => Local.from_local_datetime(dt).single().map(|x| x.with_timezone(&Utc)),
+    };
+    result.ok_or_else(|| anyhow::anyhow!("{} は存在しないか、あいまいな日時です(夏時間の切り替えなど)", dt))
+}
+
+// tz(なければこのコンピュータのタイムゾーン)での日時
+pub fn to_local(time: DateTime<Utc>, tz: Option<Tz>) -> DateTime<FixedOffset> {
+    let offset = match tz {
+        Some(tz) => tz.offset_from_utc_datetime(&time.naive_utc()).fix(),
+        None => Local.offset_from_utc_datetime(&time.naive_utc()),
+    };
+    time.with_timezone(&offset)
+}
+
+// 60x(倍速)、200m / 1.5km(動画1秒あたりの距離) の形式の再生速度
+// 単位がない場合は、距離基準なら m、そうでなければ倍速とみなします
+fn parse_speed(value: &str, by_distance: bool) -> Result<Playback> {
+    let (number, playback): (&str, fn(f64) -> Playback) = if let Some(x) = value.strip_suffix("km") {
+        (x, |x| Playback::Distance(x * 1000.0))
+    } else if let Some(x) = value.strip_suffix('m') {
+        (x, Playback::Distance)
+    } else if let Some(x) = value.strip_suffix(['x', 'X']) {
+        (x, Playback::Speed)
+    } else if by_distance {
+        (value, Playback::Distance)
+    } else {
+        (value, Playback::Speed)
+    };
 
-    pub fn get_end_date(&self) -> Option<DateTime<Utc>> {
-        get_date_parameter(&self.end_dt)
+    match number.parse::<f64>() {
+        Ok(x) if x > 0.0 => Ok(playback(x)),
+        _ => Err(anyhow::anyhow!("再生速度が正しくありません: {}", value)),
     }
 }
 
-fn get_date_parameter(date_str: &Option<String>) -> Option<DateTime<Utc>> {
-    if date_str.is_none() {
-        return None;
+// 90s, 2m, 1h30m, 90(秒) の形式の時間
+// 0以下の時間はエラーにします
+pub fn parse_duration(value: &str) -> Result<Duration> {
+    let error = || anyhow::anyhow!("時間の指定が正しくありません: {}", value);
+    let positive = |seconds: f64| {
+        let duration = Duration::milliseconds((seconds * 1000.0) as i64);
+        if duration > Duration::zero() {
+            Ok(duration)
+        } else {
+            Err(anyhow::anyhow!("時間は0より長くしてください: {}", value))
+        }
+    };
+
+    if let Ok(seconds) = value.parse::<f64>() {
+        return positive(seconds);
+    }
+
+    let mut total = 0.0;
+    let mut number = String::new();
+    for c in value.chars() {
+        if c.is_ascii_digit() || c == '.' {
+            number.push(c);
+            continue;
+        }
+
+        let unit = match c {
+            'h' => 3600.0,
+            'm' => 60.0,
+            's' => 1.0,
+            _ => return Err(error()),
+        };
+        total += number.parse::<f64>().map_err(|_| error())? * unit;
+        number.clear();
+    }
+    if !number.is_empty() {
+        return Err(error());
     }
 
-    let dt = date_str.clone().unwrap();
-    let dt = NaiveDateTime::parse_from_str(&dt, "%Y-%m-%d %H:%M:%S")
-        .expect("日付パラメータが正しいフォーマットではありません");
-    let dt: DateTime<Utc> = Local.from_local_datetime(&dt).unwrap().into();
+    positive(total)
+}
+
+#[test]
+fn duration_parameter() {
+    assert_eq!(parse_duration("90s").unwrap(), Duration::seconds(90));
+    assert_eq!(parse_duration("1m30s").unwrap(), Duration::seconds(90));
+    assert_eq!(parse_duration("1h").unwrap(), Duration::hours(1));
+    assert_eq!(parse_duration("45").unwrap(), Duration::seconds(45));
+    assert!(parse_duration("10x").is_err());
+    assert!(parse_duration("m").is_err());
+    assert!(parse_duration("-5").is_err());
+    assert!(parse_duration("0s").is_err());
+
+    assert_eq!(parse_speed("60x", false).unwrap(), Playback::Speed(60.0));
+    assert_eq!(parse_speed("1.5km", false).unwrap(), Playback::Distance(1500.0));
+    assert_eq!(parse_speed("200", true).unwrap(), Playback::Distance(200.0));
+    assert!(parse_speed("-2x", false).is_err());
+}
+
+#[test]
+fn date_parameter() {
+    let tz = Some(chrono_tz::Asia::Tokyo);
+    let track_start = Utc.ymd(2020, 7, 31).and_hms(22, 0, 0);
+    let resolve = |value: &str| parse_date_parameter(value, tz).unwrap().resolve(track_start, tz).unwrap();
+
+    assert_eq!(resolve("2020-08-01 09:00:00"), Utc.ymd(2020, 8, 1).and_hms(0, 0, 0));
+    assert_eq!(resolve("2020-08-01T09:00:00+09:00"), Utc.ymd(2020, 8, 1).and_hms(0, 0, 0));
+    assert_eq!(resolve("2020-08-01T00:00:00Z"), Utc.ymd(2020, 8, 1).and_hms(0, 0, 0));
+    // 時刻のみは日本時間でのトラックの開始日(8月1日)
+    assert_eq!(resolve("09:30"), Utc.ymd(2020, 8, 1).and_hms(0, 30, 0));
+    assert_eq!(resolve("+15m"), Utc.ymd(2020, 7, 31).and_hms(22, 15, 0));
+    assert!(parse_date_parameter("2020-08-01", tz).is_err());
+    assert!(parse_date_parameter("+15x", tz).is_err());
 
-    Some(dt)
+    // 夏時間の切り替えで存在しない日時
+    let new_york = Some(chrono_tz::America::New_York);
+    assert!(parse_date_parameter("2020-03-08 02:30:00", new_york).is_err());
 }
@@ -0,0 +1,177 @@
+// おかしなポイント(飛び値、重複)の削除
+use crate::track_point::{self, TrackLog, TrackPoint};
+use std::fmt;
+
+// 削除した理由
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CleanReason {
+    // 直前のポイントと同じ時刻
+    DuplicateTime,
+    // 前後のポイントと同じ位置(移動していない)
+    DuplicatePosition,
+    // ありえない速度で飛んで戻ってくる
+    Speed,
+    // ありえない加速・減速をして戻ってくる
+    Acceleration,
+}
+
+impl fmt::Display for CleanReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let text = match self {
+            CleanReason::DuplicateTime => "重複した時刻",
+            CleanReason::DuplicatePosition => "移動のない重複",
+            CleanReason::Speed => "速度の異常",
+            CleanReason::Acceleration => "加速度の異常",
+        };
+        write!(f, "{}", text)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct CleanConfig {
+    // 最高速度(km/h)
+    pub max_speed: f64,
+    // 最大加速度(m/s^2)
+    pub max_acceleration: f64,
+}
+
+// 削除したポイントと理由
+#[derive(Debug, Clone, Default)]
+pub struct CleanReport {
+    pub removed: Vec<(TrackPoint, CleanReason)>,
+}
+
+impl CleanReport {
+    pub fn count(&self, reason: CleanReason) -> usize {
+        self.removed.iter().filter(|(_, r)| *r == reason).count()
+    }
+}
+
+impl fmt::Display for CleanReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let reasons = [
+            CleanReason::DuplicateTime,
+            CleanReason::DuplicatePosition,
+            CleanReason::Speed,
+            CleanReason::Acceleration,
+        ];
+        let counts: Vec<String> = reasons
+            .iter()
+            .map(|reason| format!("{} {}", reason, self.count(*reason)))
+            .collect();
+        write!(f, "{} ポイントを削除しました ({})", self.removed.len(), counts.join("、"))
+    }
+}
+
+// 速度(m/s)
+fn speed(a: &TrackPoint, b: &TrackPoint) -> Option<f64> {
+    let seconds = (b.time - a.time).num_milliseconds() as f64 / 1000.0;
+    if seconds > 0.0 {
+        Some(track_point::distance(a, b) / seconds)
+    } else {
+        None
+    }
+}
+
+// 2つの区間の速度の変化(m/s^2)
+fn acceleration(a: &TrackPoint, b: &TrackPoint, c: &TrackPoint) -> Option<f64> {
+    let seconds = (c.time - a.time).num_milliseconds() as f64 / 2000.0;
+    Some((speed(b, c)? - speed(a, b)?).abs() / seconds)
+}
+
+// セグメントごとにおかしなポイントを削除します
+pub fn clean_track(track: &mut TrackLog, config: &CleanConfig) -> CleanReport {
+    let mut report = CleanReport::default();
+
+    for segment in &mut track.segments {
+        let points = std::mem::take(segment);
+        let max_speed = config.max_speed / 3.6;
+
+        for (index, point) in points.iter().enumerate() {
+            let next = points.get(index + 1);
+            let reason = match segment.last() {
+                None => None,
+                Some(last) if point.time == last.time => Some(CleanReason::DuplicateTime),
+                Some(last)
+                    if point.lat == last.lat
+                        && point.lng == last.lng
+                        && next.is_some_and(|next| next.lat == point.lat && next.lng == point.lng) =>
+                {
+                    // 同じ位置が続く場合は、最初と最後だけを残します
+                    Some(CleanReason::DuplicatePosition)
+                }
+                Some(last) => {
+                    // 飛んで行って戻ってくる(次のポイントへもありえない速度になる)場合だけ削除します
+                    let too_fast = |a: &TrackPoint, b: &TrackPoint| speed(a, b).is_some_and(|v| v > max_speed);
+                    let next_too_fast = next.is_none_or(|next| too_fast(point, next));
+
+                    let before = segment.len().checked_sub(2).map(|x| segment[x]);
+                    let too_sudden = |a: Option<&TrackPoint>, b: &TrackPoint, c: Option<&TrackPoint>| {
+                        match (a, c) {
+                            (Some(a), Some(c)) => {
+                                acceleration(a, b, c).is_some_and(|x| x > config.max_acceleration)
+                            }
+                            _ => false,
+                        }
+                    };
+
+                    if too_fast(last, point) && next_too_fast {
+                        Some(CleanReason::Speed)
+                    } else if too_sudden(before.as_ref(), last, Some(point))
+                        && too_sudden(Some(last), point, next)
+                    {
+                        Some(CleanReason::Acceleration)
+                    } else {
+                        None
+                    }
+                }
+            };
+
+            match reason {
+                Some(reason) => report.removed.push((*point, reason)),
+                None => segment.push(*point),
+            }
+        }
+    }
+
+    report
+}
+
+#[test]
+fn clean_spikes() {
+    use crate::track_point::{test_point, test_track};
+
+    // 1秒ごとに約10m北へ進む(時速36km)
+    let mut points: Vec<TrackPoint> = (0..20).map(|i| test_point(i, 35.0 + i as f64 * 0.00009)).collect();
+
+    // 5秒目が500m東へ飛ぶ
+    points[5].lng += 0.0055;
+    // 10秒目と同じ時刻のポイント
+    points.insert(11, test_point(10, 35.1));
+    // 止まっている間の同じ位置のポイント
+    let last = points[points.len() - 1];
+    points.extend((1..=5).map(|i| TrackPoint { time: last.time + chrono::Duration::seconds(i), ..last }));
+    // 12秒目が急に20m先へ進んで戻る(時速150kmまでは出ていない)
+    points[13].lat += 0.00018;
+
+    let mut track = test_track(points);
+    let report = clean_track(
+        &mut track,
+        &CleanConfig {
+            max_speed: 150.0,
+            max_acceleration: 10.0,
+        },
+    );
+
+    assert_eq!(report.count(CleanReason::Speed), 1);
+    assert_eq!(report.count(CleanReason::DuplicateTime), 1);
+    assert_eq!(report.count(CleanReason::DuplicatePosition), 4);
+    assert_eq!(report.count(CleanReason::Acceleration), 1);
+    assert_eq!(track.segments[0].len(), 20 - 2 + 1);
+    assert!(track.segments[0].iter().all(|p| p.lng == 139.5));
+
+    // 時刻が逆行しているポイントは重複として扱いません(--time-order に任せます)
+    let mut track = test_track(vec![test_point(0, 35.0), test_point(2, 35.0002), test_point(1, 35.0001)]);
+    let report = clean_track(&mut track, &CleanConfig { max_speed: 150.0, max_acceleration: 10.0 });
+    assert_eq!(report.removed.len(), 0);
+}
@@ -0,0 +1,260 @@
+// 標高からの登りの検出と分類(Strava と同じ、距離(m)×平均勾配(%)のスコアによる4級〜超級)
+use crate::{
+    stats,
+    track_point::{self, TrackLog, TrackPoint},
+};
+use chrono::{DateTime, Utc};
+use serde_json::json;
+use std::fmt;
+
+// 登りとみなす最短の距離(m)と最小の平均勾配(%)
+const MIN_LENGTH: f64 = 500.0;
+const MIN_GRADE: f64 = 3.0;
+// 登りの途中の下りはこの標高差(m)まで許します
+const DESCENT_TOLERANCE: f64 = 10.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ClimbCategory {
+    Cat4,
+    Cat3,
+    Cat2,
+    Cat1,
+    Hc,
+}
+
+impl ClimbCategory {
+    // スコア(距離(m)×平均勾配(%))の分類(4級に満たなければ None)
+    pub fn from_score(score: f64) -> Option<Self> {
+        if score >= 80_000.0 {
+            Some(ClimbCategory::Hc)
+        } else if score >= 64_000.0 {
+            Some(ClimbCategory::Cat1)
+        } else if score >= 32_000.0 {
+            Some(ClimbCategory::Cat2)
+        } else if score >= 16_000.0 {
+            Some(ClimbCategory::Cat3)
+        } else if score >= 8_000.0 {
+            Some(ClimbCategory::Cat4)
+        } else {
+            None
+        }
+    }
+
+    // 動画に表示する "CAT 4" や "HC"
+    pub fn label(&self) -> &'static str {
+        match self {
+            ClimbCategory::Cat4 => "CAT 4",
+            ClimbCategory::Cat3 => "CAT 3",
+            ClimbCategory::Cat2 => "CAT 2",
+            ClimbCategory::Cat1 => "CAT 1",
+            ClimbCategory::Hc => "HC",
+        }
+    }
+}
+
+impl fmt::Display for ClimbCategory {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let text = match self {
+            ClimbCategory::Cat4 => "4級",
+            ClimbCategory::Cat3 => "3級",
+            ClimbCategory::Cat2 => "2級",
+            ClimbCategory::Cat1 => "1級",
+            ClimbCategory::Hc => "超級",
+        };
+        write!(f, "{}", text)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Climb {
+    // 1から始まる登りの番号
+    pub number: usize,
+    pub category: ClimbCategory,
+    // 登り口と山頂の時刻
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    // トラックの開始から登り口までの距離(m、セグメントの間の移動は含めません)
+    pub start_distance: f64,
+    // 距離(m)と標高差(m)
+    pub length: f64,
+    pub gain: f64,
+    pub summit_ele: f64,
+    // 平均勾配と最大勾配(%)
+    pub avg_grade: f64,
+    pub max_grade: f64,
+    // 山頂の緯度・経度
+    pub summit: (f64, f64),
+}
+
+impl Climb {
+    // 登り口で表示する "CAT 2 CLIMB 5.20KM 6.1% +317M"
+    pub fn label(&self) -> String {
+        format!(
+            "{} CLIMB {:.2}KM {:.1}% +{:.0}M",
+            self.category.label(),
+            self.length / 1000.0,
+            self.avg_grade,
+            self.gain
+        )
+    }
+
+    pub fn to_json(self) -> serde_json::Value {
+        json!({
+            "number": self.number,
+            "category": self.category.label(),
+            "start": self.start.to_rfc3339(),
+            "end": self.end.to_rfc3339(),
+            "start_distance_m": self.start_distance,
+            "length_m": self.length,
+            "gain_m": self.gain,
+            "summit_ele_m": self.summit_ele,
+            "avg_grade_percent": self.avg_grade,
+            "max_grade_percent": self.max_grade,
+            "summit": { "lat": self.summit.0, "lon": self.summit.1 },
+        })
+    }
+}
+
+impl fmt::Display for Climb {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "登り {} ({}): {:.2} km地点から {:.2} km 平均 {:.1} % 最大 {:.1} % +{:.0} m 山頂 {:.0} m",
+            self.number,
+            self.category,
+            self.start_distance / 1000.0,
+            self.length / 1000.0,
+            self.avg_grade,
+            self.max_grade,
+            self.gain,
+            self.summit_ele
+        )
+    }
+}
+
+// 標高のあるポイントから登りを探します
+// 最も低い地点から、DESCENT_TOLERANCE を超えて下るまでの最も高い地点までを一つの登りの候補にします
+pub fn detect_climbs(track: &TrackLog) -> Vec<Climb> {
+    let mut climbs = Vec::new();
+    let mut offset = 0.0;
+
+    for segment in &track.segments {
+        let mut distances = vec![0.0];
+        for pair in segment.windows(2) {
+            distances.push(distances[distances.len() - 1] + track_point::distance(&pair[0], &pair[1]));
+        }
+
+        // 標高のあるポイントの番号と標高
+        let elevations: Vec<(usize, f64)> = segment
+            .iter()
+            .enumerate()
+            .filter_map(|(index, point)| point.ele.map(|ele| (index, ele)))
+            .collect();
+
+        let mut start = 0;
+        let mut peak = 0;
+        for current in 0..elevations.len() {
+            let ele = elevations[current].1;
+            if ele > elevations[peak].1 {
+                peak = current;
+            } else if ele < elevations[peak].1 - DESCENT_TOLERANCE {
+                climbs.extend(make_climb(segment, &distances, elevations[start].0, elevations[peak].0, offset));
+                start = current;
+                peak = current;
+            } else if ele <= elevations[start].1 {
+                // 登りが始まる前の平坦や下りでは、登り口を進めます
+                start = current;
+                peak = current;
+            }
+        }
+        if !elevations.is_empty() {
+            climbs.extend(make_climb(segment, &distances, elevations[start].0, elevations[peak].0, offset));
+        }
+
+        offset += distances[distances.len() - 1];
+    }
+
+    for (index, climb) in climbs.iter_mut().enumerate() {
+        climb.number = index + 1;
+    }
+    climbs
+}
+
+// 登りの候補から、山頂までの平均勾配が MIN_GRADE 以上になる最も低い地点を登り口にします
+// (長い緩やかなアプローチは含めません)
+fn make_climb(segment: &[TrackPoint], distances: &[f64], from: usize, peak: usize, offset: f64) -> Option<Climb> {
+    let summit_ele = segment[peak].ele?;
+    let grade = |index: usize| {
+        let length = distances[peak] - distances[index];
+        let ele = segment[index].ele?;
+        if length >= MIN_LENGTH && (summit_ele - ele) / length * 100.0 >= MIN_GRADE {
+            Some(ele)
+        } else {
+            None
+        }
+    };
+    let start = (from..peak)
+        .filter_map(|index| grade(index).map(|ele| (index, ele)))
+        .fold(None, |lowest: Option<(usize, f64)>, x| match lowest {
+            Some(lowest) if lowest.1 < x.1 => Some(lowest),
+            _ => Some(x),
+        })?
+        .0;
+
+    let length = distances[peak] - distances[start];
+    let gain = summit_ele - segment[start].ele?;
+    let avg_grade = gain / length * 100.0;
+    let category = ClimbCategory::from_score(length * avg_grade)?;
+
+    Some(Climb {
+        number: 0,
+        category,
+        start: segment[start].time,
+        end: segment[peak].time,
+        start_distance: offset + distances[start],
+        length,
+        gain,
+        summit_ele,
+        avg_grade,
+        max_grade: stats::steepest_grade(&segment[start..=peak], &distances[start..=peak])
+            .unwrap_or(avg_grade)
+            .max(avg_grade),
+        summit: (segment[peak].lat, segment[peak].lng),
+    })
+}
+
+#[test]
+fn detect_hill_climbs() {
+    use crate::track_point::{test_point, test_time, test_track};
+
+    // 1秒ごとに約10m北へ進む。平坦1kmのあと2kmを平均5%で登り(途中で5m下る)、1km下ってから短い坂を登る
+    let step = 10.0 / 111_195.0;
+    let elevation = |i: i64| match i {
+        0..=100 => 100.0,
+        101..=200 => 100.0 + (i - 100) as f64 * 0.6,
+        201..=210 => 160.0 - (i - 200) as f64 * 0.5,
+        211..=300 => 155.0 + (i - 210) as f64 * 0.5,
+        301..=400 => 200.0 - (i - 300) as f64 * 0.5,
+        _ => 150.0 + (i - 400) as f64 * 0.4,
+    };
+    let points: Vec<TrackPoint> = (0..=440)
+        .map(|i| TrackPoint {
+            ele: Some(elevation(i)),
+            ..test_point(i, 35.0 + i as f64 * step)
+        })
+        .collect();
+    let track = test_track(points);
+
+    let climbs = detect_climbs(&track);
+    assert_eq!(climbs.len(), 1);
+    let climb = climbs[0];
+    assert_eq!((climb.start, climb.end), (test_time(100), test_time(300)));
+    assert!((climb.start_distance - 1000.0).abs() < 1.0 && (climb.length - 2000.0).abs() < 1.0);
+    assert!((climb.gain - 100.0).abs() < 1e-6 && (climb.avg_grade - 5.0).abs() < 0.01);
+    assert!((climb.max_grade - 6.0).abs() < 0.01);
+    assert_eq!(climb.category, ClimbCategory::Cat4);
+    assert_eq!(climb.label(), "CAT 4 CLIMB 2.00KM 5.0% +100M");
+
+    assert_eq!(ClimbCategory::from_score(7_999.0), None);
+    assert_eq!(ClimbCategory::from_score(100_000.0), Some(ClimbCategory::Hc));
+}
@@ -0,0 +1,271 @@
+// ローカルのDEM(数値標高モデル)による標高の補正
+//
+// 次のどちらかの形式のファイルを --dem のディレクトリに置きます
+// - SRTM: N35E139.hgt (1201x1201 または 3601x3601、ビッグエンディアンの i16、北西端から)
+// - 地理院タイル(標高タイル): {z}/{x}/{y}.txt または {z}/{x}/{y}.png
+//   https://maps.gsi.go.jp/development/demtile.html
+
+use crate::track_point::TrackLog;
+use anyhow::Result;
+use std::{
+    collections::HashMap,
+    f64::consts::PI,
+    fs,
+    path::{Path, PathBuf},
+};
+
+const GSI_TILE_SIZE: usize = 256;
+
+// SRTMの欠損値
+const HGT_VOID: i16 = -32768;
+
+// SRTMの1タイル分(SRTM1では約1300万点になるので、ファイルの値のまま持ちます)
+struct HgtTile {
+    size: usize,
+    values: Vec<i16>,
+}
+
+impl HgtTile {
+    fn value(&self, col: usize, row: usize) -> Option<f64> {
+        match self.values[row * self.size + col] {
+            HGT_VOID => None,
+            value => Some(value as f64),
+        }
+    }
+}
+
+// 地理院タイルの1タイル分
+type GsiTile = Vec<Option<f64>>;
+
+pub struct Dem {
+    dir: PathBuf,
+    gsi_zooms: Vec<u32>,
+    hgt_cache: HashMap<(i32, i32), Option<HgtTile>>,
+    gsi_cache: HashMap<(u32, i64, i64), Option<GsiTile>>,
+}
+
+impl Dem {
+    pub fn open(dir: &str) -> Result<Self> {
+        let dir = PathBuf::from(dir);
+        if !dir.is_dir() {
+            return Err(anyhow::anyhow!(
+                "DEMのディレクトリがみつかりません: {}",
+                dir.display()
+            ));
+        }
+
+        // 地理院タイルのズームレベル(数字のディレクトリ)を詳細な順に並べます
+        let mut gsi_zooms: Vec<u32> = fs::read_dir(&dir)?
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().is_dir())
+            .filter_map(|entry| entry.file_name().to_str()?.parse().ok())
+            .collect();
+        gsi_zooms.sort_unstable_by(|a, b| b.cmp(a));
+
+        Ok(Self {
+            dir,
+            gsi_zooms,
+            hgt_cache: HashMap::new(),
+            gsi_cache: HashMap::new(),
+        })
+    }
+
+    // 指定位置の標高(データがない場合は None)
+    pub fn elevation(&mut self, lat: f64, lng: f64) -> Option<f64> {
+        self.hgt_elevation(lat, lng)
+            .or_else(|| self.gsi_elevation(lat, lng))
+    }
+
+    fn hgt_elevation(&mut self, lat: f64, lng: f64) -> Option<f64> {
+        let key = (lat.floor() as i32, lng.floor() as i32);
+        let dir = &self.dir;
+        let tile = self
+            .hgt_cache
+            .entry(key)
+            .or_insert_with(|| read_hgt(&dir.join(hgt_file_name(key.0, key.1))))
+            .as_ref()?;
+
+        // 北西端が (0, 0)
+        let last = (tile.size - 1) as f64;
+        let x = (lng - key.1 as f64) * last;
+        let y = (key.0 as f64 + 1.0 - lat) * last;
+
+        bilinear(x, y, |col, row| {
+            if col < 0 || row < 0 || col as usize >= tile.size || row as usize >= tile.size {
+                return None;
+            }
+            tile.value(col as usize, row as usize)
+        })
+    }
+
+    fn gsi_elevation(&mut self, lat: f64, lng: f64) -> Option<f64> {
+        for zoom in self.gsi_zooms.clone() {
+            // ピクセルの中心が値の位置なので 0.5 ずらします
+            let (x, y) = global_pixel(lat, lng, zoom);
+            let value = bilinear(x - 0.5, y - 0.5, |px, py| self.gsi_pixel(zoom, px, py));
+            if value.is_some() {
+                return value;
+            }
+        }
+
+        None
+    }
+
+    fn gsi_pixel(&mut self, zoom: u32, px: i64, py: i64) -> Option<f64> {
+        let size = GSI_TILE_SIZE as i64;
+        let (tile_x, tile_y) = (px.div_euclid(size), py.div_euclid(size));
+        let dir = &self.dir;
+        let tile = self
+            .gsi_cache
+            .entry((zoom, tile_x, tile_y))
+            .or_insert_with(|| read_gsi_tile(dir, zoom, tile_x, tile_y))
+            .as_ref()?;
+
+        let (col, row) = (px.rem_euclid(size) as usize, py.rem_euclid(size) as usize);
+        tile[row * GSI_TILE_SIZE + col]
+    }
+}
+
+// 標高をDEMの値で置き換えます(weight < 1.0 の場合は記録された値と混ぜます)
+pub fn correct_track(track: &mut TrackLog, dem: &mut Dem, weight: f64) -> usize {
+    let mut count = 0;
+
+    for point in track.segments.iter_mut().flatten() {
+        if let Some(terrain) = dem.elevation(point.lat, point.lng) {
+            point.ele = Some(match point.ele {
+                Some(ele) => ele * (1.0 - weight) + terrain * weight,
+                None => terrain,
+            });
+            count += 1;
+        }
+    }
+
+    count
+}
+
+// N35E139.hgt / S13W071.hgt
+fn hgt_file_name(lat: i32, lng: i32) -> String {
+    format!(
+        "{}{:02}{}{:03}.hgt",
+        if lat < 0 { 'S' } else { 'N' },
+        lat.abs(),
+        if lng < 0 { 'W' } else { 'E' },
+        lng.abs()
+    )
+}
+
+fn read_hgt(path: &Path) -> Option<HgtTile> {
+    let data = fs::read(path).ok()?;
+    let size = ((data.len() / 2) as f64).sqrt() as usize;
+    if size < 2 || size * size * 2 != data.len() {
+        return None;
+    }
+
+    let values = data
+        .chunks_exact(2)
+        .map(|x| i16::from_be_bytes([x[0], x[1]]))
+        .collect();
+
+    Some(HgtTile { size, values })
+}
+
+fn read_gsi_tile(dir: &Path, zoom: u32, tile_x: i64, tile_y: i64) -> Option<GsiTile> {
+    let base = dir.join(zoom.to_string()).join(tile_x.to_string());
+
+    // テキスト形式: 256行 x 256列のカンマ区切り(データなしは "e")
+    if let Ok(text) = fs::read_to_string(base.join(format!("{}.txt", tile_y))) {
+        let values: GsiTile = text
+            .lines()
+            .flat_map(|line| line.split(','))
+            .map(|value| value.trim().parse().ok())
+            .collect();
+        return if values.len() == GSI_TILE_SIZE * GSI_TILE_SIZE {
+            Some(values)
+        } else {
+            None
+        };
+    }
+
+    // PNG形式: x = 2^16R + 2^8G + B、x < 2^23 なら x * 0.01m、x > 2^23 なら (x - 2^24) * 0.01m
+    let image = image::open(base.join(format!("{}.png", tile_y)))
+        .ok()?
+        .to_rgb();
+    if image.width() as usize != GSI_TILE_SIZE || image.height() as usize != GSI_TILE_SIZE {
+        return None;
+    }
+
+    let values = image
+        .pixels()
+        .map(|pixel| {
+            let x = ((pixel[0] as i64) << 16) + ((pixel[1] as i64) << 8) + pixel[2] as i64;
+            match x {
+                0x80_0000 => None,
+                x if x < 0x80_0000 => Some(x as f64 * 0.01),
+                x => Some((x - 0x100_0000) as f64 * 0.01),
+            }
+        })
+        .collect();
+    Some(values)
+}
+
+// ズームレベル zoom での世界座標(ピクセル単位)
+fn global_pixel(lat: f64, lng: f64, zoom: u32) -> (f64, f64) {
+    let world = (GSI_TILE_SIZE as f64) * 2f64.powi(zoom as i32);
+    let lat = lat.to_radians();
+
+    let x = (lng + 180.0) / 360.0 * world;
+    let y = (1.0 - (lat.tan() + 1.0 / lat.cos()).ln() / PI) / 2.0 * world;
+    (x, y)
+}
+
+// 格子点の値から(x, y)の値を双線形補間します(欠損がある場合は残りの点で重み付けします)
+fn bilinear(x: f64, y: f64, mut value: impl FnMut(i64, i64) -> Option<f64>) -> Option<f64> {
+    let (x0, y0) = (x.floor(), y.floor());
+    let (fx, fy) = (x - x0, y - y0);
+    let (x0, y0) = (x0 as i64, y0 as i64);
+
+    let samples = [
+        (value(x0, y0), (1.0 - fx) * (1.0 - fy)),
+        (value(x0 + 1, y0), fx * (1.0 - fy)),
+        (value(x0, y0 + 1), (1.0 - fx) * fy),
+        (value(x0 + 1, y0 + 1), fx * fy),
+    ];
+
+    let (sum, weight) = samples
+        .iter()
+        .filter_map(|(value, weight)| value.map(|v| (v * weight, *weight)))
+        .fold((0.0, 0.0), |(s, w), (v, weight)| (s + v, w + weight));
+
+    if weight > 0.0 {
+        Some(sum / weight)
+    } else {
+        None
+    }
+}
+
+#[test]
+fn hgt_bilinear() {
+    // 3x3 のタイル(北端 100m、南端 300m)
+    let dir = std::env::temp_dir().join(format!("gpx_to_map_hgt_bilinear_{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let values: [i16; 9] = [100, 100, 100, 200, 200, -32768, 300, 300, 300];
+    let data: Vec<u8> = values
+        .iter()
+        .flat_map(|v| v.to_be_bytes().to_vec())
+        .collect();
+    fs::write(dir.join(hgt_file_name(35, 139)), data).unwrap();
+
+    let mut dem = Dem::open(dir.to_str().unwrap()).unwrap();
+    let mut elevation = |lat: f64, lng: f64| dem.elevation(lat, lng).map(|x| x.round());
+    assert_eq!(elevation(36.0 - 1e-9, 139.0), Some(100.0));
+    assert_eq!(elevation(35.75, 139.25), Some(150.0));
+    assert_eq!(elevation(35.5, 139.5), Some(200.0));
+
+    // 欠損している格子点は使わない
+    assert_eq!(elevation(35.5, 140.0 - 1e-9), Some(200.0));
+
+    // ファイルがない場所
+    assert_eq!(elevation(10.0, 10.0), None);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
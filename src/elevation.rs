@@ -0,0 +1,156 @@
+// 標高の平滑化と獲得標高の計算
+use crate::track_point::{TrackLog, TrackPoint};
+use chrono::{DateTime, Duration, Utc};
+use std::str::FromStr;
+
+// 標高の平滑化フィルタ
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ElevationFilter {
+    None,
+    // 前後 window 秒の平均
+    Average,
+    // 前後 window 秒の中央値(飛び値に強い)
+    Median,
+}
+
+impl FromStr for ElevationFilter {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(ElevationFilter::None),
+            "average" => Ok(ElevationFilter::Average),
+            "median" => Ok(ElevationFilter::Median),
+            _ => Err(anyhow::anyhow!("標高のフィルタは none, average, median のいずれかです")),
+        }
+    }
+}
+
+// セグメントごとに標高を平滑化します(window は前後それぞれの秒数)
+pub fn smooth_track(track: &mut TrackLog, filter: ElevationFilter, window: Duration) {
+    if filter == ElevationFilter::None {
+        return;
+    }
+
+    for segment in &mut track.segments {
+        smooth_points(segment, filter, window);
+    }
+}
+
+fn smooth_points(points: &mut [TrackPoint], filter: ElevationFilter, window: Duration) {
+    let source: Vec<(DateTime<Utc>, Option<f64>)> = points.iter().map(|p| (p.time, p.ele)).collect();
+
+    let mut start = 0;
+    let mut end = 0;
+    for (index, point) in points.iter_mut().enumerate() {
+        if point.ele.is_none() {
+            continue;
+        }
+
+        // 時間の窓 [start, end) を進めます
+        let time = source[index].0;
+        while source[start].0 < time - window {
+            start += 1;
+        }
+        while end < source.len() && source[end].0 <= time + window {
+            end += 1;
+        }
+
+        let mut values: Vec<f64> = source[start..end].iter().filter_map(|(_, ele)| *ele).collect();
+        point.ele = match filter {
+            ElevationFilter::Average => Some(values.iter().sum::<f64>() / values.len() as f64),
+            ElevationFilter::Median => {
+                values.sort_by(|a, b| a.partial_cmp(b).unwrap());
+                Some(values[values.len() / 2])
+            }
+            ElevationFilter::None => point.ele,
+        };
+    }
+}
+
+// ポイントごとの累積獲得標高・累積下降量
+// 直前に採用した標高から threshold 以上変化したときだけ加算するので、細かな揺れは数えません
+pub fn cumulative_climb(points: &[TrackPoint], threshold: f64) -> Vec<(f64, f64)> {
+    let mut result = Vec::with_capacity(points.len());
+    let mut reference: Option<f64> = None;
+    let (mut ascent, mut descent) = (0.0, 0.0);
+
+    for point in points {
+        if let Some(ele) = point.ele {
+            match reference {
+                Some(r) if ele - r >= threshold => {
+                    ascent += ele - r;
+                    reference = Some(ele);
+                }
+                Some(r) if r - ele >= threshold => {
+                    descent += r - ele;
+                    reference = Some(ele);
+                }
+                Some(_) => {}
+                None => reference = Some(ele),
+            }
+        }
+        result.push((ascent, descent));
+    }
+
+    result
+}
+
+// 時刻ごとの累積獲得標高(動画への表示用)
+pub struct ClimbProfile {
+    times: Vec<DateTime<Utc>>,
+    ascents: Vec<f64>,
+}
+
+impl ClimbProfile {
+    pub fn new(track: &TrackLog, threshold: f64) -> Self {
+        let mut times = Vec::new();
+        let mut ascents = Vec::new();
+        let mut offset = 0.0;
+
+        for segment in &track.segments {
+            let climb = cumulative_climb(segment, threshold);
+            for (point, (ascent, _)) in segment.iter().zip(&climb) {
+                times.push(point.time);
+                ascents.push(offset + ascent);
+            }
+            offset += climb.last().map(|(ascent, _)| *ascent).unwrap_or(0.0);
+        }
+
+        Self { times, ascents }
+    }
+
+    // 指定時刻までの獲得標高
+    pub fn ascent_at(&self, time: DateTime<Utc>) -> f64 {
+        match self.times.binary_search(&time) {
+            Ok(index) => self.ascents[index],
+            Err(0) => 0.0,
+            Err(index) => self.ascents[index - 1],
+        }
+    }
+}
+
+#[test]
+fn climb_with_noise() {
+    use crate::track_point::test_point;
+
+    let point = |sec: i64, ele: f64| TrackPoint {
+        ele: Some(ele),
+        ..test_point(sec, 35.0)
+    };
+
+    // 100mで±2mの揺れが続いたあと、200mまで登る
+    let mut points: Vec<TrackPoint> = (0..100)
+        .map(|i| point(i, if i % 2 == 0 { 102.0 } else { 98.0 }))
+        .collect();
+    points.extend((0..=100).map(|i| point(100 + i, 100.0 + i as f64)));
+
+    let climb = cumulative_climb(&points, 5.0);
+    let (ascent, descent) = *climb.last().unwrap();
+    assert!((ascent - 100.0).abs() <= 5.0, "ascent: {}", ascent);
+    assert!(descent < 5.0, "descent: {}", descent);
+
+    // 平滑化すると揺れが小さくなる
+    smooth_points(&mut points, ElevationFilter::Average, Duration::seconds(5));
+    assert!((points[10].ele.unwrap() - 100.0).abs() < 1.0);
+}
@@ -0,0 +1,204 @@
+// 処理したトラック(またはフレームごとの位置)の GPX 1.1、GeoJSON、CSV への出力
+use crate::track_point::{self, TrackLog, TrackPoint};
+use anyhow::Result;
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde_json::json;
+use std::{fs::File, io::Write, path::Path};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExportFormat {
+    Gpx,
+    GeoJson,
+    Csv,
+}
+
+impl ExportFormat {
+    // 拡張子から出力形式を判断します
+    pub fn from_path(path: &str) -> Result<Self> {
+        let extension = Path::new(path)
+            .extension()
+            .and_then(|x| x.to_str())
+            .map(|x| x.to_lowercase());
+
+        match extension.as_deref() {
+            Some("gpx") => Ok(ExportFormat::Gpx),
+            Some("geojson") | Some("json") => Ok(ExportFormat::GeoJson),
+            Some("csv") => Ok(ExportFormat::Csv),
+            _ => Err(anyhow::anyhow!("出力ファイル \"{}\" の拡張子は gpx, geojson, csv のいずれかにしてください", path)),
+        }
+    }
+}
+
+// start から end までのポイントだけを残します(空になったセグメントは除きます)
+pub fn clip(track: &TrackLog, start: Option<DateTime<Utc>>, end: Option<DateTime<Utc>>) -> TrackLog {
+    let segments = track
+        .segments
+        .iter()
+        .map(|segment| {
+            segment
+                .iter()
+                .filter(|point| start.is_none_or(|start| point.time >= start) && end.is_none_or(|end| point.time <= end))
+                .copied()
+                .collect::<Vec<TrackPoint>>()
+        })
+        .filter(|segment| !segment.is_empty())
+        .collect();
+
+    TrackLog {
+        segments,
+        ..track.clone()
+    }
+}
+
+pub fn export(track: &TrackLog, path: &str) -> Result<()> {
+    let format = ExportFormat::from_path(path)?;
+    let mut file = File::create(path)?;
+
+    match format {
+        ExportFormat::Gpx => write_gpx(track, &mut file),
+        ExportFormat::GeoJson => write_geojson(track, &mut file),
+        ExportFormat::Csv => write_csv(track, &mut file),
+    }
+}
+
+fn write_gpx(track: &TrackLog, writer: &mut impl Write) -> Result<()> {
+    let mut gpx = gpx::Gpx {
+        version: gpx::GpxVersion::Gpx11,
+        ..Default::default()
+    };
+    gpx.tracks.push(track.to_gpx_track());
+    gpx.waypoints = track
+        .waypoints
+        .iter()
+        .map(|point| {
+            let mut waypoint = gpx::Waypoint::new(geo_types::Point::new(point.lng, point.lat));
+            waypoint.name = Some(point.name.clone());
+            waypoint
+        })
+        .collect();
+
+    gpx::write(&gpx, writer).map_err(|x| anyhow::anyhow!(x.to_string()))
+}
+
+// セグメントを MultiLineString にして、時刻は coordinateProperties に入れます
+fn write_geojson(track: &TrackLog, writer: &mut impl Write) -> Result<()> {
+    let coordinates: Vec<Vec<Vec<f64>>> = track
+        .segments
+        .iter()
+        .map(|segment| {
+            segment
+                .iter()
+                .map(|point| match point.ele {
+                    Some(ele) => vec![point.lng, point.lat, ele],
+                    None => vec![point.lng, point.lat],
+                })
+                .collect()
+        })
+        .collect();
+    let times: Vec<Vec<String>> = track
+        .segments
+        .iter()
+        .map(|segment| segment.iter().map(|point| format_time(point.time)).collect())
+        .collect();
+
+    let mut features = vec![json!({
+        "type": "Feature",
+        "properties": {
+            "name": track.name,
+            "coordinateProperties": { "times": times },
+        },
+        "geometry": { "type": "MultiLineString", "coordinates": coordinates },
+    })];
+    features.extend(track.waypoints.iter().map(|waypoint| {
+        json!({
+            "type": "Feature",
+            "properties": { "name": waypoint.name },
+            "geometry": { "type": "Point", "coordinates": [waypoint.lng, waypoint.lat] },
+        })
+    }));
+
+    let collection = json!({ "type": "FeatureCollection", "features": features });
+    serde_json::to_writer_pretty(&mut *writer, &collection)?;
+    writeln!(writer)?;
+    Ok(())
+}
+
+// 速度(km/h)は直前のポイントから、距離(m)はトラックの開始から(セグメントの間の移動は含めません)
+fn write_csv(track: &TrackLog, writer: &mut impl Write) -> Result<()> {
+    writeln!(writer, "time,lat,lon,ele,speed,distance")?;
+
+    let mut total = 0.0;
+    for segment in &track.segments {
+        for (index, point) in segment.iter().enumerate() {
+            let mut speed = 0.0;
+            if index > 0 {
+                let prev = &segment[index - 1];
+                let distance = track_point::distance(prev, point);
+                let seconds = (point.time - prev.time).num_milliseconds() as f64 / 1000.0;
+                if seconds > 0.0 {
+                    speed = distance / seconds * 3.6;
+                }
+                total += distance;
+            }
+
+            writeln!(
+                writer,
+                "{},{:.7},{:.7},{},{:.1},{:.1}",
+                format_time(point.time),
+                point.lat,
+                point.lng,
+                point.ele.map(|ele| format!("{:.1}", ele)).unwrap_or_default(),
+                speed,
+                total
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
+fn format_time(time: DateTime<Utc>) -> String {
+    time.to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+#[test]
+fn export_formats() {
+    use chrono::{Duration, TimeZone};
+
+    // 1秒ごとに約10m北へ進む(時速36km)
+    let start = Utc.ymd(2020, 8, 1).and_hms(0, 0, 0);
+    let step = 10.0 / 111_195.0;
+    let points: Vec<TrackPoint> = (0..10)
+        .map(|i| TrackPoint {
+            ele: Some(100.0 + i as f64),
+            ..TrackPoint::new(start + Duration::seconds(i), 35.0 + i as f64 * step, 139.5)
+        })
+        .collect();
+    let track = TrackLog {
+        name: Some("test".to_string()),
+        segments: vec![points],
+        waypoints: Vec::new(),
+    };
+
+    let track = clip(&track, Some(start + Duration::seconds(2)), Some(start + Duration::seconds(5)));
+    assert_eq!(track.segments[0].len(), 4);
+
+    let mut csv = Vec::new();
+    write_csv(&track, &mut csv).unwrap();
+    let csv = String::from_utf8(csv).unwrap();
+    let lines: Vec<&str> = csv.lines().collect();
+    assert_eq!(lines.len(), 5);
+    assert_eq!(lines[1], "2020-08-01T00:00:02.000Z,35.0001799,139.5000000,102.0,0.0,0.0");
+    assert_eq!(lines[4], "2020-08-01T00:00:05.000Z,35.0004497,139.5000000,105.0,36.0,30.0");
+
+    let mut geojson = Vec::new();
+    write_geojson(&track, &mut geojson).unwrap();
+    let value: serde_json::Value = serde_json::from_slice(&geojson).unwrap();
+    assert_eq!(value["features"][0]["geometry"]["coordinates"][0][3][2], 105.0);
+    assert_eq!(value["features"][0]["properties"]["coordinateProperties"]["times"][0][0], "2020-08-01T00:00:02.000Z");
+
+    let mut gpx = Vec::new();
+    write_gpx(&track, &mut gpx).unwrap();
+    let gpx = gpx::read(gpx.as_slice()).unwrap();
+    assert_eq!(gpx.tracks[0].segments[0].points.len(), 4);
+}
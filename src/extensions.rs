@@ -0,0 +1,108 @@
+// gpxのextensionsに含まれるセンサーデータの読み込み(gpxクレートは読み飛ばすため)
+//
+// <trkpt lat="..." lon="...">
+//   <extensions>
+//     <gpxtpx:TrackPointExtension>
+//       <gpxtpx:atemp>28</gpxtpx:atemp><gpxtpx:hr>142</gpxtpx:hr><gpxtpx:cad>85</gpxtpx:cad>
+//     </gpxtpx:TrackPointExtension>
+//     <power>210</power>
+//   </extensions>
+// </trkpt>
+
+use anyhow::Result;
+use std::io::Read;
+use xml::reader::{EventReader, XmlEvent};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct SensorData {
+    pub hr: Option<f64>,
+    pub cad: Option<f64>,
+    pub power: Option<f64>,
+    pub temp: Option<f64>,
+}
+
+// トラック > セグメント > ポイント の順にセンサーデータを返します
+pub fn read_track_sensors<R: Read>(reader: R) -> Result<Vec<Vec<Vec<SensorData>>>> {
+    let mut tracks: Vec<Vec<Vec<SensorData>>> = Vec::new();
+    let mut in_extensions = false;
+    let mut element = String::new();
+
+    for event in EventReader::new(reader) {
+        match event? {
+            XmlEvent::StartElement { name, .. } => {
+                match name.local_name.as_str() {
+                    "trk" => tracks.push(Vec::new()),
+                    "trkseg" => {
+                        if let Some(track) = tracks.last_mut() {
+                            track.push(Vec::new());
+                        }
+                    }
+                    "trkpt" => {
+                        if let Some(segment) = tracks.last_mut().and_then(|x| x.last_mut()) {
+                            segment.push(SensorData::default());
+                        }
+                    }
+                    "extensions" => in_extensions = true,
+                    _ => {}
+                }
+                element = name.local_name;
+            }
+            XmlEvent::EndElement { name } => {
+                if name.local_name == "extensions" {
+                    in_extensions = false;
+                }
+                element.clear();
+            }
+            XmlEvent::Characters(text) if in_extensions => {
+                let point = match tracks
+                    .last_mut()
+                    .and_then(|x| x.last_mut())
+                    .and_then(|x| x.last_mut())
+                {
+                    Some(point) => point,
+                    None => continue,
+                };
+                let value = text.trim().parse::<f64>().ok();
+
+                match element.as_str() {
+                    "hr" => point.hr = value,
+                    "cad" => point.cad = value,
+                    "power" | "PowerInWatts" => point.power = value,
+                    "atemp" => point.temp = value,
+                    "wtemp" => point.temp = point.temp.or(value),
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Ok(tracks)
+}
+
+#[test]
+fn read_sensors() {
+    let text = r#"<?xml version="1.0" encoding="UTF-8"?>
+<gpx version="1.1" creator="test" xmlns="http://www.topografix.com/GPX/1/1" xmlns:gpxtpx="http://www.garmin.com/xmlschemas/TrackPointExtension/v1">
+<trk><trkseg>
+<trkpt lat="35.0" lon="139.0"><ele>100</ele><time>2020-07-31T22:26:40Z</time>
+<extensions><gpxtpx:TrackPointExtension><gpxtpx:atemp>28</gpxtpx:atemp><gpxtpx:hr>142</gpxtpx:hr><gpxtpx:cad>85</gpxtpx:cad></gpxtpx:TrackPointExtension><power>210</power></extensions>
+</trkpt>
+<trkpt lat="35.1" lon="139.1"><time>2020-07-31T22:26:41Z</time></trkpt>
+</trkseg></trk>
+</gpx>"#;
+
+    let tracks = read_track_sensors(text.as_bytes()).unwrap();
+    assert_eq!(tracks.len(), 1);
+    assert_eq!(tracks[0][0].len(), 2);
+    assert_eq!(
+        tracks[0][0][0],
+        SensorData {
+            hr: Some(142.0),
+            cad: Some(85.0),
+            power: Some(210.0),
+            temp: Some(28.0),
+        }
+    );
+    assert_eq!(tracks[0][0][1], SensorData::default());
+}
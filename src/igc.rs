@@ -0,0 +1,197 @@
+// IGCフォーマット(パラグライダー等のフライトログ)の読み込み
+// https://xp-soaring.github.io/igc_file_format/igc_format_2008.html
+
+use crate::track_point::{TrackLog, TrackPoint, Waypoint};
+use anyhow::Result;
+use chrono::{DateTime, Duration, NaiveDate, NaiveTime, TimeZone, Utc};
+use std::io::BufRead;
+
+// Bレコード一件分
+#[derive(Debug, Clone, Copy)]
+struct IgcFix {
+    time: NaiveTime,
+    lat: f64,
+    lng: f64,
+    pressure_alt: Option<f64>,
+    gnss_alt: Option<f64>,
+}
+
+pub fn read<R: BufRead>(reader: R) -> Result<TrackLog> {
+    let mut date: Option<NaiveDate> = None;
+    let mut fixes: Vec<IgcFix> = Vec::new();
+    let mut waypoints: Vec<Waypoint> = Vec::new();
+
+    for (index, line) in reader.lines().enumerate() {
+        let line = line?;
+        let line = line.trim_end();
+
+        if line.starts_with("HFDTE") {
+            date = Some(parse_date(line).ok_or_else(|| {
+                anyhow::anyhow!("IGCの日付が不正です({}行目): {}", index + 1, line)
+            })?);
+        } else if line.starts_with('B') {
+            let fix = parse_fix(line).ok_or_else(|| {
+                anyhow::anyhow!("IGCのBレコードが不正です({}行目): {}", index + 1, line)
+            })?;
+            fixes.push(fix);
+        } else if line.starts_with('C') {
+            // 先頭のCレコード(宣言日時など)は座標を持たないので読み飛ばします
+            if let Some(waypoint) = parse_turnpoint(line) {
+                waypoints.push(waypoint);
+            }
+        }
+    }
+
+    let date = date.ok_or_else(|| anyhow::anyhow!("IGCファイルに日付(HFDTE)がありません"))?;
+
+    // 時刻が戻ったら日付をまたいだとみなします
+    let mut day = Utc.from_utc_date(&date);
+    let mut prev_time: Option<NaiveTime> = None;
+    let mut points: Vec<TrackPoint> = Vec::new();
+    for fix in fixes {
+        if let Some(prev) = prev_time {
+            if fix.time < prev {
+                day = day + Duration::days(1);
+            }
+        }
+        prev_time = Some(fix.time);
+
+        let time: DateTime<Utc> = day.and_time(fix.time).unwrap();
+        points.push(TrackPoint {
+            ele: fix.elevation(),
+            ..TrackPoint::new(time, fix.lat, fix.lng)
+        });
+    }
+
+    Ok(TrackLog {
+        name: None,
+        segments: vec![points],
+        waypoints,
+    })
+}
+
+// HFDTE010720 / HFDTEDATE:010720,01
+fn parse_date(line: &str) -> Option<NaiveDate> {
+    let text = line.trim_start_matches("HFDTE");
+    let text = text.trim_start_matches("DATE:");
+    let text = text.get(0..6)?;
+
+    let day: u32 = text.get(0..2)?.parse().ok()?;
+    let month: u32 = text.get(2..4)?.parse().ok()?;
+    let year: i32 = text.get(4..6)?.parse().ok()?;
+    let year = if year < 80 { 2000 + year } else { 1900 + year };
+
+    NaiveDate::from_ymd_opt(year, month, day)
+}
+
+// B HHMMSS DDMMmmmN DDDMMmmmE V PPPPP GGGGG
+fn parse_fix(line: &str) -> Option<IgcFix> {
+    let hour: u32 = line.get(1..3)?.parse().ok()?;
+    let min: u32 = line.get(3..5)?.parse().ok()?;
+    let sec: u32 = line.get(5..7)?.parse().ok()?;
+    let time = NaiveTime::from_hms_opt(hour, min, sec)?;
+
+    let (lat, lng) = parse_coordinate(line.get(7..24)?)?;
+
+    // 気圧高度、GNSS高度
+    let altitude = |text: Option<&str>| -> Option<f64> { text?.parse().ok() };
+    let pressure_alt = altitude(line.get(25..30));
+    let gnss_alt = altitude(line.get(30..35));
+
+    Some(IgcFix {
+        time,
+        lat,
+        lng,
+        pressure_alt,
+        gnss_alt,
+    })
+}
+
+impl IgcFix {
+    // 気圧高度を優先します
+    // 気圧計のない記録器は気圧高度を0にするので、その場合はGNSS高度を使い、両方0の場合は未計測とみなします
+    fn elevation(&self) -> Option<f64> {
+        match (self.pressure_alt, self.gnss_alt) {
+            (Some(0.0), Some(0.0)) => None,
+            (Some(0.0), Some(gnss)) => Some(gnss),
+            (pressure, gnss) => pressure.or(gnss),
+        }
+    }
+}
+
+// C DDMMmmmN DDDMMmmmE 名前
+fn parse_turnpoint(line: &str) -> Option<Waypoint> {
+    let (lat, lng) = parse_coordinate(line.get(1..18)?)?;
+
+    // 座標未設定のターンポイントは無視します
+    if lat == 0.0 && lng == 0.0 {
+        return None;
+    }
+
+    Some(Waypoint {
+        name: line.get(18..).unwrap_or("").trim().to_string(),
+        lat,
+        lng,
+    })
+}
+
+// DDMMmmmN DDDMMmmmE を緯度経度に変換します
+fn parse_coordinate(text: &str) -> Option<(f64, f64)> {
+    let lat_deg: f64 = text.get(0..2)?.parse().ok()?;
+    let lat_min: f64 = text.get(2..7)?.parse().ok()?;
+    let lat = lat_deg + lat_min / 60000.0;
+    let lat = match text.get(7..8)? {
+        "N" => lat,
+        "S" => -lat,
+        _ => return None,
+    };
+
+    let lng_deg: f64 = text.get(8..11)?.parse().ok()?;
+    let lng_min: f64 = text.get(11..16)?.parse().ok()?;
+    let lng = lng_deg + lng_min / 60000.0;
+    let lng = match text.get(16..17)? {
+        "E" => lng,
+        "W" => -lng,
+        _ => return None,
+    };
+
+    Some((lat, lng))
+}
+
+#[test]
+fn read_igc() {
+    let text = "AXXXABC FLIGHT:1
+HFDTE310720
+HFPLTPILOTINCHARGE:Taro Yamada
+C310720120000310720000103
+C0000000N00000000ETAKEOFF
+C3544694N13951939ESTART
+C3537000N13912000ETP1
+B2359583544694N13951939EA0010300110
+B0000013544607N13951942EA0009100095
+B0000023544549N13951947WV0000000082
+B0000033544549N13951947WA0000500000
+B0000043544549N13951947WA0000000000
+";
+    let log = read(text.as_bytes()).unwrap();
+
+    assert_eq!(log.waypoints.len(), 2);
+    assert_eq!(log.waypoints[0].name, "START");
+
+    let points = &log.segments[0];
+    assert_eq!(points.len(), 5);
+    assert!((points[0].lat - (35.0 + 44.694 / 60.0)).abs() < 1e-9);
+    assert!((points[0].lng - (139.0 + 51.939 / 60.0)).abs() < 1e-9);
+    assert_eq!(points[0].ele, Some(103.0));
+
+    // 日付をまたいだ場合
+    assert_eq!(points[1].time.to_rfc3339(), "2020-08-01T00:00:01+00:00");
+
+    // 気圧高度がない場合はGNSS高度、西経は負の値
+    assert_eq!(points[2].ele, Some(82.0));
+    assert!(points[2].lng < 0.0);
+
+    // 0mも高度として扱い、両方0の場合だけ未計測とみなす
+    assert_eq!(points[3].ele, Some(5.0));
+    assert_eq!(points[4].ele, None);
+}
@@ -0,0 +1,58 @@
+// ファイル内のトラック・セグメントの一覧表示
+use crate::{arguments, loader::TrackFile, track_point::TrackPoint};
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
+
+pub fn print_info(path: &str, file: &TrackFile, tz: Option<Tz>) {
+    println!("{}", path);
+
+    for (index, track) in file.tracks.iter().enumerate() {
+        let points: usize = track.segments.iter().map(|segment| segment.len()).sum();
+        println!(
+            "[{}] {} セグメント数: {} ポイント数: {} {}",
+            index,
+            track.name.as_deref().unwrap_or("(名前なし)"),
+            track.segments.len(),
+            points,
+            format_range(track.time_range(), tz),
+        );
+
+        for (segment_index, segment) in track.segments.iter().enumerate() {
+            println!(
+                "    ({}) ポイント数: {} {}",
+                segment_index,
+                segment.len(),
+                format_range(segment_range(segment), tz),
+            );
+        }
+    }
+
+    if !file.waypoints.is_empty() {
+        println!("ウェイポイント数: {}", file.waypoints.len());
+        for waypoint in &file.waypoints {
+            println!("    {} ({:.5}, {:.5})", waypoint.name, waypoint.lat, waypoint.lng);
+        }
+    }
+}
+
+fn segment_range(segment: &[TrackPoint]) -> Option<(DateTime<Utc>, DateTime<Utc>)> {
+    Some((segment.first()?.time, segment.last()?.time))
+}
+
+// --start-dt / --end-dt にそのまま使えるように tz(なければこのコンピュータのタイムゾーン)の日時で表示します
+fn format_range(range: Option<(DateTime<Utc>, DateTime<Utc>)>, tz: Option<Tz>) -> String {
+    match range {
+        Some((start, end)) => {
+            let duration = end - start;
+            format!(
+                "{} - {} ({}:{:02}:{:02})",
+                arguments::to_local(start, tz).format("%Y-%m-%d %H:%M:%S"),
+                arguments::to_local(end, tz).format("%Y-%m-%d %H:%M:%S"),
+                duration.num_hours(),
+                duration.num_minutes() % 60,
+                duration.num_seconds() % 60,
+            )
+        }
+        None => "時刻のあるポイントがありません".to_string(),
+    }
+}
@@ -0,0 +1,323 @@
+// ラップ・スプリットの検出(セグメント、ラップのマーカー、一定距離、スタートラインの通過)
+use crate::track_point::{self, TrackLog, TrackPoint};
+use chrono::{DateTime, Duration, Utc};
+use std::{fmt, str::FromStr};
+
+// スタートラインの通過をラップとみなす最短の間隔(秒)。ライン付近の揺れで何度も数えないようにします
+const MIN_LAP_SECONDS: i64 = 10;
+// ラップのマーカーを通過したとみなす半径(m)
+const MARKER_RADIUS: f64 = 50.0;
+
+// ラップの区切り方の種類(距離とスタートラインは別のオプションで指定します)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LapKind {
+    None,
+    Segments,
+    Markers,
+    Distance,
+    Line,
+}
+
+impl FromStr for LapKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(LapKind::None),
+            "segments" => Ok(LapKind::Segments),
+            "markers" => Ok(LapKind::Markers),
+            "distance" => Ok(LapKind::Distance),
+            "line" => Ok(LapKind::Line),
+            _ => Err(anyhow::anyhow!("ラップの区切り方は none, segments, markers, distance, line のいずれかです")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum LapMode {
+    None,
+    // gpxのセグメントごと
+    Segments,
+    // 名前が "Lap" や "ラップ" で始まるウェイポイント(FITから変換したラップの位置)に最も近づいた地点
+    Markers,
+    // 一定の距離(m)ごと
+    Distance(f64),
+    // 2点(緯度, 経度)を結ぶスタートラインを通過するごと
+    Line((f64, f64), (f64, f64)),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Split {
+    // 1から始まるラップの番号
+    pub number: usize,
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    // 距離(m)
+    pub distance: f64,
+}
+
+impl Split {
+    pub fn duration(&self) -> Duration {
+        self.end - self.start
+    }
+
+    // 平均速度(km/h)
+    pub fn avg_speed(&self) -> f64 {
+        let seconds = self.duration().num_milliseconds() as f64 / 1000.0;
+        if seconds > 0.0 {
+            self.distance / seconds * 3.6
+        } else {
+            0.0
+        }
+    }
+
+    // 動画に表示する "LAP 3 5.00KM 10:23 28.9KM/H"
+    pub fn label(&self) -> String {
+        format!(
+            "LAP {} {:.2}KM {} {:.1}KM/H",
+            self.number,
+            self.distance / 1000.0,
+            format_duration(self.duration()),
+            self.avg_speed()
+        )
+    }
+}
+
+impl fmt::Display for Split {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "ラップ {}: {:.2} km {} {:.1} km/h",
+            self.number,
+            self.distance / 1000.0,
+            format_duration(self.duration()),
+            self.avg_speed()
+        )
+    }
+}
+
+pub fn format_duration(duration: Duration) -> String {
+    let seconds = duration.num_seconds();
+    if seconds >= 3600 {
+        format!("{}:{:02}:{:02}", seconds / 3600, seconds / 60 % 60, seconds % 60)
+    } else {
+        format!("{}:{:02}", seconds / 60, seconds % 60)
+    }
+}
+
+fn is_lap_marker(name: &str) -> bool {
+    name.to_lowercase().starts_with("lap") || name.starts_with("ラップ")
+}
+
+// トラックをラップに分けます(ラップの区切りがなければトラック全体を一つのラップにします)
+pub fn detect_splits(track: &TrackLog, mode: &LapMode) -> Vec<Split> {
+    let (track_start, track_end) = match track.time_range() {
+        Some(range) => range,
+        None => return Vec::new(),
+    };
+
+    let points = cumulative_distances(track);
+    let total = points.last().map_or(0.0, |(_, distance)| *distance);
+
+    let mut boundaries: Vec<DateTime<Utc>> = match mode {
+        LapMode::None => Vec::new(),
+        LapMode::Segments => track.segments.iter().skip(1).filter_map(|segment| segment.first()).map(|point| point.time).collect(),
+        LapMode::Markers => marker_times(track, &points, track_start),
+        LapMode::Distance(distance) if *distance > 0.0 => (1..)
+            .map(|count| count as f64 * distance)
+            .take_while(|target| *target < total)
+            .map(|target| time_at_distance(&points, target))
+            .collect(),
+        LapMode::Distance(_) => Vec::new(),
+        LapMode::Line(from, to) => line_crossings(track, *from, *to),
+    };
+    boundaries.retain(|time| track_start < *time && *time < track_end);
+    boundaries.sort();
+    boundaries.dedup();
+
+    let mut times = vec![track_start];
+    times.extend(boundaries);
+    times.push(track_end);
+
+    times
+        .windows(2)
+        .enumerate()
+        .map(|(index, pair)| Split {
+            number: index + 1,
+            start: pair[0],
+            end: pair[1],
+            distance: distance_at_time(&points, pair[1]) - distance_at_time(&points, pair[0]),
+        })
+        .collect()
+}
+
+// ラップのマーカーを順に、前のマーカーの位置から先で最初に近づいた時刻
+// マーカーの半径内に入ってから出るまでで最も近い地点とし、半径内に入らない場合は残りで最も近い地点とします
+fn marker_times(track: &TrackLog, points: &[(&TrackPoint, f64)], track_start: DateTime<Utc>) -> Vec<DateTime<Utc>> {
+    let mut times = Vec::new();
+    let mut from = 0;
+
+    for waypoint in track.waypoints.iter().filter(|waypoint| is_lap_marker(&waypoint.name)) {
+        let target = TrackPoint::new(track_start, waypoint.lat, waypoint.lng);
+        let distances: Vec<f64> = points[from..]
+            .iter()
+            .map(|(point, _)| track_point::distance(point, &target))
+            .collect();
+
+        let (range, leave) = match distances.iter().position(|d| *d <= MARKER_RADIUS) {
+            Some(enter) => {
+                let leave = distances[enter..]
+                    .iter()
+                    .position(|d| *d > MARKER_RADIUS)
+                    .map_or(distances.len(), |count| enter + count);
+                (enter..leave, Some(leave))
+            }
+            None => (0..distances.len(), None),
+        };
+        if let Some(index) = range.min_by(|a, b| distances[*a].partial_cmp(&distances[*b]).unwrap()) {
+            times.push(points[from + index].0.time);
+            // 次のマーカーは半径から出た所(半径内に入らなかった場合はこの地点の次)から探します
+            from += leave.unwrap_or(index + 1);
+        }
+    }
+
+    times
+}
+
+// ポイントとトラックの開始からの距離(セグメントの間の移動は含めません)
+pub fn cumulative_distances(track: &TrackLog) -> Vec<(&TrackPoint, f64)> {
+    let mut points: Vec<(&TrackPoint, f64)> = Vec::new();
+    let mut total = 0.0;
+    for segment in &track.segments {
+        for (index, point) in segment.iter().enumerate() {
+            if index > 0 {
+                total += track_point::distance(&segment[index - 1], point);
+            }
+            points.push((point, total));
+        }
+    }
+    points
+}
+
+fn time_at_distance(points: &[(&TrackPoint, f64)], distance: f64) -> DateTime<Utc> {
+    let index = points.partition_point(|(_, d)| *d < distance);
+    if index == 0 {
+        return points[0].0.time;
+    }
+    let ((prev, prev_distance), (next, next_distance)) = (points[index - 1], points[index]);
+    let ratio = (distance - prev_distance) / (next_distance - prev_distance);
+    prev.time + Duration::milliseconds(((next.time - prev.time).num_milliseconds() as f64 * ratio).round() as i64)
+}
+
+// 前後のポイントの間は補間します
+pub fn distance_at_time(points: &[(&TrackPoint, f64)], time: DateTime<Utc>) -> f64 {
+    let index = points.partition_point(|(point, _)| point.time < time);
+    if index == 0 {
+        return points[0].1;
+    }
+    if index >= points.len() {
+        return points[points.len() - 1].1;
+    }
+    let ((prev, prev_distance), (next, next_distance)) = (points[index - 1], points[index]);
+    let span = (next.time - prev.time).num_milliseconds() as f64;
+    if span <= 0.0 {
+        return next_distance;
+    }
+    prev_distance + (next_distance - prev_distance) * (time - prev.time).num_milliseconds() as f64 / span
+}
+
+// スタートライン(緯度, 経度の2点)を横切った時刻
+pub fn line_crossings(track: &TrackLog, from: (f64, f64), to: (f64, f64)) -> Vec<DateTime<Utc>> {
+    // 経度を緯度に合わせて縮めた平面で交差を調べます
+    let scale = from.0.to_radians().cos();
+    let xy = |lat: f64, lng: f64| (lng * scale, lat);
+    let (a, b) = (xy(from.0, from.1), xy(to.0, to.1));
+
+    let mut crossings: Vec<DateTime<Utc>> = Vec::new();
+    for segment in &track.segments {
+        for pair in segment.windows(2) {
+            let (p, q) = (xy(pair[0].lat, pair[0].lng), xy(pair[1].lat, pair[1].lng));
+            let denominator = (q.0 - p.0) * (b.1 - a.1) - (q.1 - p.1) * (b.0 - a.0);
+            if denominator == 0.0 {
+                continue;
+            }
+
+            // t はトラックの区間上、u はライン上の位置(0.0〜1.0)
+            let t = ((a.0 - p.0) * (b.1 - a.1) - (a.1 - p.1) * (b.0 - a.0)) / denominator;
+            let u = ((a.0 - p.0) * (q.1 - p.1) - (a.1 - p.1) * (q.0 - p.0)) / denominator;
+            if !(0.0..1.0).contains(&t) || !(0.0..=1.0).contains(&u) {
+                continue;
+            }
+
+            let millis = ((pair[1].time - pair[0].time).num_milliseconds() as f64 * t).round() as i64;
+            let time = pair[0].time + Duration::milliseconds(millis);
+            if crossings.last().is_none_or(|last| time - *last >= Duration::seconds(MIN_LAP_SECONDS)) {
+                crossings.push(time);
+            }
+        }
+    }
+
+    crossings
+}
+
+#[test]
+fn detect_laps() {
+    use crate::track_point::{test_point, test_time, test_track};
+    use crate::{overlay::Notifier, track_point::Waypoint};
+
+    // 1秒ごとに約10m、北へ1km進んで戻り、また北へ1km進む
+    let step = 10.0 / 111_195.0;
+    let position = |sec: i64| {
+        let offset = if (100..200).contains(&sec) { 200 - sec } else { sec % 200 };
+        35.0 + offset as f64 * step
+    };
+    let points: Vec<TrackPoint> = (0..=300).map(|sec| test_point(sec, position(sec))).collect();
+    let mut track = TrackLog {
+        waypoints: vec![Waypoint {
+            name: "LAP 1".to_string(),
+            lat: 35.0 + 100.0 * step,
+            lng: 139.5001,
+        }],
+        ..test_track(points.clone())
+    };
+
+    let splits = detect_splits(&track, &LapMode::Distance(1000.0));
+    assert_eq!(splits.len(), 3);
+    assert_eq!((splits[1].start, splits[1].end), (test_time(100), test_time(200)));
+    assert!((splits[1].avg_speed() - 36.0).abs() < 0.1);
+    assert_eq!(splits[1].label(), "LAP 2 1.00KM 1:40 36.0KM/H");
+
+    // 500m地点を東西に横切るライン
+    let line = LapMode::Line((35.0 + 50.5 * step, 139.49), (35.0 + 50.5 * step, 139.51));
+    let ends: Vec<DateTime<Utc>> = detect_splits(&track, &line).iter().map(|split| split.end).collect();
+    assert_eq!(ends, vec![test_time(50) + Duration::milliseconds(500), test_time(149) + Duration::milliseconds(500), test_time(250) + Duration::milliseconds(500), test_time(300)]);
+
+    // 最初に最も近づいた地点
+    let splits = detect_splits(&track, &LapMode::Markers);
+    assert_eq!(splits.len(), 2);
+    assert_eq!(splits[0].end, test_time(100));
+
+    // 通知はラップの終わりから3フレーム
+    let mut notifier = Notifier::new(splits.iter().map(|split| (split.end, *split)).collect(), 3);
+    assert_eq!(notifier.update(test_time(99)), None);
+    assert_eq!(notifier.update(test_time(100)).map(|split| split.number), Some(1));
+    assert!(notifier.update(test_time(101)).is_some() && notifier.update(test_time(102)).is_some());
+    assert_eq!(notifier.update(test_time(103)), None);
+
+    // 同じ場所のマーカーは、前のマーカーより後に通過した順に対応させる
+    track.waypoints = (1..=3)
+        .map(|number| Waypoint {
+            name: format!("LAP {}", number),
+            lat: 35.0 + 50.0 * step,
+            lng: 139.5001,
+        })
+        .collect();
+    let ends: Vec<DateTime<Utc>> = detect_splits(&track, &LapMode::Markers).iter().map(|split| split.end).collect();
+    assert_eq!(ends, vec![test_time(50), test_time(150), test_time(250), test_time(300)]);
+
+    track.segments = vec![points[..=150].to_vec(), points[151..].to_vec()];
+    let splits = detect_splits(&track, &LapMode::Segments);
+    assert_eq!(splits.len(), 2);
+    assert_eq!(splits[1].start, test_time(151));
+    assert_eq!(detect_splits(&track, &LapMode::None).len(), 1);
+}
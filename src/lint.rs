@@ -0,0 +1,307 @@
+// トラックの検査(時刻の抜けや逆行、重複、ギャップ、座標の範囲、タイムゾーンの疑い)と修正
+use crate::track_point::{self, TrackPoint};
+use chrono::{DateTime, Duration, TimeZone, Timelike, Utc};
+use std::fmt;
+
+// タイムゾーンの混在を疑う時刻のずれの許容範囲(秒)
+const TIMEZONE_TOLERANCE: i64 = 60;
+// タイムゾーンの混在を疑う移動距離の上限(m)
+const TIMEZONE_DISTANCE: f64 = 1000.0;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum LintKind {
+    // 時刻がない(描画では読み飛ばされます)、連続するポイントはまとめて数えます
+    MissingTime(usize),
+    // 直前のポイントより前の時刻
+    Backwards(Duration),
+    // 直前のポイントと同じ時刻
+    DuplicateTime,
+    // 直前のポイントと間隔が空いている
+    Gap(Duration),
+    // 緯度・経度が範囲外、または 0, 0
+    OutOfRange,
+    // 時刻がおかしい(未来、GPSの週番号のロールオーバーなど)
+    SuspiciousTime(DateTime<Utc>),
+    // ちょうど N 時間ずれている(タイムゾーンの混在)
+    TimezoneJump(i64),
+    // 深夜ばかりの記録(現地時刻をUTCとして記録している)
+    LocalTimeAsUtc,
+}
+
+impl fmt::Display for LintKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LintKind::MissingTime(1) => write!(f, "時刻がありません"),
+            LintKind::MissingTime(count) => write!(f, "時刻がありません({}ポイント)", count),
+            LintKind::Backwards(duration) => write!(f, "時刻が {}秒 逆行しています", -duration.num_seconds()),
+            LintKind::DuplicateTime => write!(f, "直前のポイントと時刻が重複しています"),
+            LintKind::Gap(duration) => write!(f, "{}秒 の間隔があります", duration.num_seconds()),
+            LintKind::OutOfRange => write!(f, "緯度・経度が範囲外です"),
+            LintKind::SuspiciousTime(time) => write!(f, "時刻がおかしい可能性があります ({})", time.to_rfc3339()),
+            LintKind::TimezoneJump(hours) => {
+                write!(f, "時刻がちょうど {}時間 ずれています(タイムゾーンの混在の可能性)", hours)
+            }
+            LintKind::LocalTimeAsUtc => write!(f, "現地時刻がUTCとして記録されている可能性があります"),
+        }
+    }
+}
+
+// 問題のある場所(ポイントは時刻のないものも含めた番号)
+#[derive(Debug, Clone, PartialEq)]
+pub struct LintIssue {
+    pub track: usize,
+    pub segment: Option<usize>,
+    pub point: Option<usize>,
+    pub kind: LintKind,
+}
+
+impl fmt::Display for LintIssue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "[{}]", self.track)?;
+        if let Some(segment) = self.segment {
+            write!(f, "({})", segment)?;
+        }
+        if let Some(point) = self.point {
+            write!(f, " #{}", point)?;
+        }
+        write!(f, " {}", self.kind)
+    }
+}
+
+fn is_out_of_range(point: &gpx::Waypoint) -> bool {
+    let (lat, lng) = (point.point().lat(), point.point().lng());
+    !(-90.0..=90.0).contains(&lat) || !(-180.0..=180.0).contains(&lng) || (lat == 0.0 && lng == 0.0)
+}
+
+fn to_track_point(point: &gpx::Waypoint, time: DateTime<Utc>) -> TrackPoint {
+    TrackPoint::new(time, point.point().lat(), point.point().lng())
+}
+
+// 全トラック・ルートを検査します(max_gap を超える間隔をギャップとして報告します)
+pub fn lint(gpx: &gpx::Gpx, max_gap: Duration, now: DateTime<Utc>) -> Vec<LintIssue> {
+    let mut issues = Vec::new();
+    let gps_epoch = Utc.ymd(2000, 1, 1).and_hms(0, 0, 0);
+
+    let mut tracks: Vec<Vec<&[gpx::Waypoint]>> = gpx
+        .tracks
+        .iter()
+        .map(|track| track.segments.iter().map(|segment| segment.points.as_slice()).collect())
+        .collect();
+    if !gpx.route.points.is_empty() {
+        tracks.push(vec![gpx.route.points.as_slice()]);
+    }
+
+    for (track_index, segments) in tracks.iter().enumerate() {
+        let mut night = 0;
+        let mut timed = 0;
+
+        for (segment_index, points) in segments.iter().enumerate() {
+            let mut prev: Option<(&gpx::Waypoint, DateTime<Utc>)> = None;
+            // 続いている時刻のないポイントの報告(issues の中の位置)
+            let mut missing: Option<usize> = None;
+
+            for (index, point) in points.iter().enumerate() {
+                let issue = |kind| LintIssue {
+                    track: track_index,
+                    segment: Some(segment_index),
+                    point: Some(index),
+                    kind,
+                };
+
+                if is_out_of_range(point) {
+                    issues.push(issue(LintKind::OutOfRange));
+                }
+
+                let time = match point.time {
+                    Some(time) => time,
+                    None => {
+                        match missing {
+                            Some(position) => {
+                                if let LintKind::MissingTime(count) = &mut issues[position].kind {
+                                    *count += 1;
+                                }
+                            }
+                            None => {
+                                missing = Some(issues.len());
+                                issues.push(issue(LintKind::MissingTime(1)));
+                            }
+                        }
+                        continue;
+                    }
+                };
+                missing = None;
+
+                if time > now || time < gps_epoch {
+                    issues.push(issue(LintKind::SuspiciousTime(time)));
+                }
+
+                if let Some((prev_point, prev_time)) = prev {
+                    let duration = time - prev_time;
+                    if duration < Duration::zero() {
+                        issues.push(issue(LintKind::Backwards(duration)));
+                    } else if duration == Duration::zero() {
+                        issues.push(issue(LintKind::DuplicateTime));
+                    } else if duration > max_gap {
+                        issues.push(issue(LintKind::Gap(duration)));
+                    }
+
+                    // ほとんど移動せずにちょうど N 時間ずれている
+                    let seconds = duration.num_seconds().abs();
+                    let hours = (seconds + TIMEZONE_TOLERANCE) / 3600;
+                    let distance = track_point::distance(
+                        &to_track_point(prev_point, prev_time),
+                        &to_track_point(point, time),
+                    );
+                    if (1..=14).contains(&hours)
+                        && (seconds - hours * 3600).abs() <= TIMEZONE_TOLERANCE
+                        && distance < TIMEZONE_DISTANCE
+                    {
+                        issues.push(issue(LintKind::TimezoneJump(hours * duration.num_seconds().signum())));
+                    }
+                }
+                prev = Some((point, time));
+
+                // 経度から求めた太陽時で深夜(0時〜4時)かどうか
+                let solar = time + Duration::seconds((point.point().lng() / 15.0 * 3600.0) as i64);
+                timed += 1;
+                if solar.hour() < 4 {
+                    night += 1;
+                }
+            }
+        }
+
+        if timed > 0 && night * 10 >= timed * 8 {
+            issues.push(LintIssue {
+                track: track_index,
+                segment: None,
+                point: None,
+                kind: LintKind::LocalTimeAsUtc,
+            });
+        }
+    }
+
+    issues
+}
+
+// 修正できる問題を修正します(範囲外のポイントの削除、時刻の補間、並べ替え、重複の削除)
+// 修正したポイントの数を返します
+pub fn repair(gpx: &mut gpx::Gpx) -> usize {
+    let mut count = 0;
+
+    let segments = gpx
+        .tracks
+        .iter_mut()
+        .flat_map(|track| track.segments.iter_mut().map(|segment| &mut segment.points))
+        .chain(std::iter::once(&mut gpx.route.points));
+
+    for points in segments {
+        let before = points.len();
+        points.retain(|point| !is_out_of_range(point));
+        count += before - points.len();
+
+        count += interpolate_times(points);
+
+        let before = points.len();
+        points.retain(|point| point.time.is_some());
+        points.sort_by_key(|point| point.time);
+        points.dedup_by_key(|point| point.time);
+        count += before - points.len();
+    }
+
+    count
+}
+
+// 前後に時刻のあるポイントの間の、時刻のないポイントに距離の比率で時刻を付けます
+fn interpolate_times(points: &mut [gpx::Waypoint]) -> usize {
+    let mut count = 0;
+    let mut prev: Option<usize> = None;
+
+    for index in 0..points.len() {
+        if points[index].time.is_none() {
+            continue;
+        }
+
+        if let Some(start) = prev.filter(|start| index > start + 1) {
+            let (start_time, end_time) = (points[start].time.unwrap(), points[index].time.unwrap());
+            let position = |point: &gpx::Waypoint| to_track_point(point, start_time);
+
+            let mut distances = vec![0.0];
+            for pair in points[start..=index].windows(2) {
+                let distance = track_point::distance(&position(&pair[0]), &position(&pair[1]));
+                distances.push(distances[distances.len() - 1] + distance);
+            }
+            let total = distances[distances.len() - 1];
+
+            for offset in 1..(index - start) {
+                let ratio = if total > 0.0 {
+                    distances[offset] / total
+                } else {
+                    offset as f64 / (index - start) as f64
+                };
+                let millis = ((end_time - start_time).num_milliseconds() as f64 * ratio).round() as i64;
+                points[start + offset].time = Some(start_time + Duration::milliseconds(millis));
+                count += 1;
+            }
+        }
+        prev = Some(index);
+    }
+
+    count
+}
+
+#[test]
+fn lint_and_repair() {
+    use crate::track_point::test_time;
+
+    let waypoint = |sec: Option<i64>, lat: f64, lng: f64| {
+        let mut point = gpx::Waypoint::new(geo_types::Point::new(lng, lat));
+        point.time = sec.map(test_time);
+        point
+    };
+
+    // 日本時間の朝7時ごろ(UTC 22時ごろ)に北へ走る
+    let mut points: Vec<gpx::Waypoint> = (0..10)
+        .map(|i| waypoint(Some(i), 35.0 + i as f64 * 0.0001, 139.5))
+        .collect();
+    points[3].time = None;
+    points[4].time = None;
+    points.swap(5, 6);
+    points.push(waypoint(Some(9), 35.001, 139.5));
+    points.push(waypoint(Some(300), 35.0011, 139.5));
+    points.push(waypoint(Some(300 + 9 * 3600), 35.0012, 139.5));
+    points.push(waypoint(Some(330 + 9 * 3600), 0.0, 0.0));
+
+    let mut segment = gpx::TrackSegment::new();
+    segment.points = points;
+    let mut track = gpx::Track::new();
+    track.segments.push(segment);
+    let mut gpx = gpx::Gpx {
+        version: gpx::GpxVersion::Gpx11,
+        ..Default::default()
+    };
+    gpx.tracks.push(track);
+
+    let now = Utc.ymd(2020, 8, 2).and_hms(0, 0, 0);
+    let issues = lint(&gpx, Duration::seconds(60), now);
+    let kinds: Vec<&LintKind> = issues.iter().map(|issue| &issue.kind).collect();
+    assert_eq!(
+        kinds,
+        vec![
+            &LintKind::MissingTime(2),
+            &LintKind::Backwards(Duration::seconds(-1)),
+            &LintKind::DuplicateTime,
+            &LintKind::Gap(Duration::seconds(291)),
+            &LintKind::Gap(Duration::seconds(9 * 3600)),
+            &LintKind::TimezoneJump(9),
+            &LintKind::OutOfRange,
+        ]
+    );
+    assert_eq!(issues[0].to_string(), "[0](0) #3 時刻がありません(2ポイント)");
+
+    // 修正後はギャップ以外の問題がなくなる
+    assert_eq!(repair(&mut gpx), 4);
+    let issues = lint(&gpx, Duration::seconds(60), now);
+    assert!(issues.iter().all(|issue| matches!(issue.kind, LintKind::Gap(_) | LintKind::TimezoneJump(_))));
+    assert_eq!(gpx.tracks[0].segments[0].points[3].time, Some(test_time(3)));
+    assert_eq!(gpx.tracks[0].segments[0].points.len(), 12);
+}
@@ -0,0 +1,333 @@
+// 入力ファイルの読み込みとトラックの選択
+use crate::{
+    extensions, igc, srt,
+    synthesis::TimeSynthesis,
+    track_point::{TrackLog, Waypoint},
+};
+use anyhow::Result;
+use chrono::{TimeZone, Utc};
+use chrono_tz::Tz;
+use std::{fs, fs::File, io::BufReader, io::Read, path::Path};
+
+// 読み込み可能なファイルの拡張子
+const TRACK_EXTENSIONS: [&str; 3] = ["gpx", "igc", "srt"];
+
+// 一つのファイルから読み込んだデータ
+#[derive(Debug, Clone)]
+pub struct TrackFile {
+    pub tracks: Vec<TrackLog>,
+    pub waypoints: Vec<Waypoint>,
+}
+
+// 小文字にした拡張子
+fn extension(path: &str) -> Option<String> {
+    Path::new(path)
+        .extension()
+        .and_then(|x| x.to_str())
+        .map(|x| x.to_lowercase())
+}
+
+// 描画するトラックの選び方
+#[derive(Debug, Clone)]
+pub enum TrackSelection {
+    First,
+    Index(usize),
+    Name(String),
+    All,
+}
+
+impl TrackSelection {
+    // --track の値(数字ならインデックス、それ以外は名前)から作成します
+    pub fn parse(value: Option<&str>, all_tracks: bool) -> Self {
+        match value {
+            _ if all_tracks => TrackSelection::All,
+            Some(value) => match value.parse() {
+                Ok(index) => TrackSelection::Index(index),
+                Err(_) => TrackSelection::Name(value.to_string()),
+            },
+            None => TrackSelection::First,
+        }
+    }
+}
+
+impl TrackFile {
+    // 拡張子から入力ファイルの形式を判断して読み込みます
+    // synthesis があれば、時刻のないgpxのセグメントに時刻を付けます
+    // タイムゾーンのない日時(SRT)は tz(なければこのコンピュータのタイムゾーン)の日時とします
+    pub fn read(path: &str, synthesis: Option<&TimeSynthesis>, tz: Option<Tz>) -> Result<Self> {
+        let f = File::open(path)?;
+        let reader = BufReader::new(f);
+
+        let mut track = match extension(path).as_deref() {
+            Some("igc") => igc::read(reader)?,
+            Some("srt") => srt::read(reader, tz)?,
+            _ => return Self::read_gpx(reader, synthesis),
+        };
+
+        let waypoints = std::mem::take(&mut track.waypoints);
+        Ok(Self {
+            tracks: vec![track],
+            waypoints,
+        })
+    }
+
+    // 複数のファイルを読み込み、トラックとウェイポイントを一つにまとめます
+    // 時刻を付ける場合、時刻が重ならないように次のファイルは前のファイルの終わりから始めます
+    pub fn read_files(paths: &[String], synthesis: Option<&TimeSynthesis>, tz: Option<Tz>) -> Result<Self> {
+        let mut result = Self {
+            tracks: Vec::new(),
+            waypoints: Vec::new(),
+        };
+        let mut synthesis = synthesis.copied();
+
+        for path in paths {
+            let file = Self::read(path, synthesis.as_ref(), tz)?;
+            if let Some(synthesis) = &mut synthesis {
+                let end = file.tracks.iter().filter_map(|track| track.time_range()).map(|(_, end)| end).max();
+                synthesis.start = end.or(synthesis.start);
+            }
+            result.tracks.extend(file.tracks);
+            result.waypoints.extend(file.waypoints);
+        }
+
+        Ok(result)
+    }
+
+    // 時刻のないポイントも含めてgpxのまま読み込みます(igc/srtはgpxに変換します)
+    pub fn read_raw_gpx(path: &str, tz: Option<Tz>) -> Result<gpx::Gpx> {
+        if extension(path).as_deref() == Some("gpx") {
+            let reader = BufReader::new(File::open(path)?);
+            return gpx::read(reader).map_err(|x| anyhow::anyhow!(x.to_string()));
+        }
+
+        let file = Self::read(path, None, tz)?;
+        let mut gpx = gpx::Gpx {
+            version: gpx::GpxVersion::Gpx11,
+            ..Default::default()
+        };
+        gpx.tracks = file.tracks.iter().map(|track| track.to_gpx_track()).collect();
+        gpx.waypoints = file
+            .waypoints
+            .iter()
+            .map(|point| {
+                let mut waypoint = gpx::Waypoint::new(geo_types::Point::new(point.lng, point.lat));
+                waypoint.name = Some(point.name.clone());
+                waypoint
+            })
+            .collect();
+
+        Ok(gpx)
+    }
+
+    // 全トラックとルート(時刻があるもの、synthesis があれば時刻のないものも)、ウェイポイントを読み込みます
+    fn read_gpx(mut reader: BufReader<File>, synthesis: Option<&TimeSynthesis>) -> Result<Self> {
+        // extensions を別に読むため、一度メモリに読み込みます
+        let mut data: Vec<u8> = Vec::new();
+        reader.read_to_end(&mut data)?;
+
+        let gpx = gpx::read(data.as_slice()).map_err(|x| anyhow::anyhow!(x.to_string()))?;
+        let sensors = extensions::read_track_sensors(data.as_slice())?;
+
+        let mut tracks: Vec<TrackLog> = gpx
+            .tracks
+            .iter()
+            .enumerate()
+            .map(|(index, track)| {
+                let sensors = sensors.get(index).map(|x| x.as_slice()).unwrap_or(&[]);
+                TrackLog::from_gpx_track(track, sensors)
+            })
+            .collect();
+        if !gpx.route.points.is_empty() {
+            tracks.push(TrackLog::from_gpx_route(&gpx.route));
+        }
+
+        if let Some(synthesis) = synthesis {
+            // 時刻のあるポイントが一つもないセグメントだけに時刻を付けます(続くセグメントは前の終わりから)
+            let mut raw: Vec<Vec<&[gpx::Waypoint]>> = gpx
+                .tracks
+                .iter()
+                .map(|track| track.segments.iter().map(|segment| segment.points.as_slice()).collect())
+                .collect();
+            if !gpx.route.points.is_empty() {
+                raw.push(vec![gpx.route.points.as_slice()]);
+            }
+
+            let now = Utc::now();
+            let mut start = synthesis
+                .start
+                .or_else(|| gpx.metadata.as_ref().and_then(|x| x.time))
+                .unwrap_or_else(|| Utc.timestamp(now.timestamp(), 0));
+            for (track, raw_segments) in tracks.iter_mut().zip(raw) {
+                for (segment, points) in track.segments.iter_mut().zip(raw_segments) {
+                    if segment.is_empty() && !points.is_empty() {
+                        *segment = synthesis.synthesize(points, start);
+                        start = segment[segment.len() - 1].time;
+                    }
+                }
+            }
+        }
+
+        let waypoints = gpx
+            .waypoints
+            .iter()
+            .map(|point| Waypoint {
+                name: point.name.clone().unwrap_or_default(),
+                lat: point.point().lat(),
+                lng: point.point().lng(),
+            })
+            .collect();
+
+        Ok(Self { tracks, waypoints })
+    }
+
+    // 選択されたトラックを返します(複数の場合は時刻順に連結します)
+    pub fn select(&self, selection: &TrackSelection) -> Result<TrackLog> {
+        let not_found = || anyhow::anyhow!("データがみつかりません");
+
+        let mut track = match selection {
+            TrackSelection::First => self.tracks.first().cloned().ok_or_else(not_found)?,
+            TrackSelection::Index(index) => self.tracks.get(*index).cloned().ok_or_else(|| {
+                anyhow::anyhow!("トラック番号 {} はありません(トラック数: {})", index, self.tracks.len())
+            })?,
+            TrackSelection::Name(name) => self
+                .tracks
+                .iter()
+                .find(|track| track.name.as_deref() == Some(name.as_str()))
+                .cloned()
+                .ok_or_else(|| anyhow::anyhow!("トラック \"{}\" はありません", name))?,
+            TrackSelection::All => TrackLog::concat(self.tracks.clone()),
+        };
+
+        if track.segments.iter().all(|segment| segment.is_empty()) {
+            return Err(not_found());
+        }
+
+        track.waypoints = self.waypoints.clone();
+        Ok(track)
+    }
+}
+
+// 読み込み可能なファイルかどうか(ディレクトリ、ワイルドカードを含む)
+pub fn is_track_path(path: &str) -> bool {
+    let path = Path::new(path);
+    path.is_dir() || has_track_extension(path)
+}
+
+fn has_track_extension(path: &Path) -> bool {
+    path.extension()
+        .and_then(|x| x.to_str())
+        .map(|x| TRACK_EXTENSIONS.contains(&x.to_lowercase().as_str()))
+        .unwrap_or(false)
+}
+
+// ディレクトリとワイルドカード(* ?)をファイル名の一覧に展開します
+pub fn expand_paths(patterns: &[String]) -> Result<Vec<String>> {
+    let mut result: Vec<String> = Vec::new();
+
+    for pattern in patterns {
+        let path = Path::new(pattern);
+        let file_name = path.file_name().and_then(|x| x.to_str()).unwrap_or("");
+
+        let mut files: Vec<String> = if path.is_dir() {
+            list_files(path, |name| has_track_extension(Path::new(name)))?
+        } else if file_name.contains(['*', '?']) {
+            let dir = match path.parent() {
+                Some(dir) if !dir.as_os_str().is_empty() => dir,
+                _ => Path::new("."),
+            };
+            list_files(dir, |name| wildcard_match(file_name, name))?
+        } else {
+            vec![pattern.clone()]
+        };
+
+        if files.is_empty() {
+            return Err(anyhow::anyhow!("処理対象のファイルがみつかりません: {}", pattern));
+        }
+
+        files.sort();
+        result.extend(files);
+    }
+
+    Ok(result)
+}
+
+fn list_files(dir: &Path, filter: impl Fn(&str) -> bool) -> Result<Vec<String>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let name = path.file_name().and_then(|x| x.to_str()).unwrap_or("");
+        if path.is_file() && filter(name) {
+            files.push(path.to_string_lossy().to_string());
+        }
+    }
+
+    Ok(files)
+}
+
+// * は任意の文字列、? は任意の一文字
+fn wildcard_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+
+    fn matches(pattern: &[char], text: &[char]) -> bool {
+        match pattern.split_first() {
+            None => text.is_empty(),
+            Some(('*', rest)) => (0..=text.len()).any(|i| matches(rest, &text[i..])),
+            Some(('?', rest)) => !text.is_empty() && matches(rest, &text[1..]),
+            Some((c, rest)) => text.first() == Some(c) && matches(rest, &text[1..]),
+        }
+    }
+
+    matches(&pattern, &text)
+}
+
+#[test]
+fn expand_wildcard() {
+    assert!(wildcard_match("*.gpx", "大垂水峠かな.gpx"));
+    assert!(wildcard_match("day?.igc", "day1.igc"));
+    assert!(!wildcard_match("day?.igc", "day10.igc"));
+
+    let files = expand_paths(&["sample_data/*.gpx".to_string()]).unwrap();
+    assert_eq!(files.len(), 1);
+    assert_eq!(expand_paths(&["sample_data".to_string()]).unwrap(), files);
+    assert!(expand_paths(&["sample_data/*.igc".to_string()]).is_err());
+}
+
+#[test]
+fn select_tracks() {
+    let file = TrackFile::read("sample_data/大垂水峠かな.gpx", None, None).unwrap();
+
+    assert_eq!(file.tracks.len(), 1);
+    assert!(file.select(&TrackSelection::parse(Some("0"), false)).is_ok());
+    assert!(file.select(&TrackSelection::parse(Some("1"), false)).is_err());
+    assert!(file.select(&TrackSelection::parse(Some("not found"), false)).is_err());
+
+    let all = file.select(&TrackSelection::parse(None, true)).unwrap();
+    assert_eq!(all.segments, file.tracks[0].segments);
+
+    // 時刻のないファイルを続けて読むと、前のファイルの終わりから時刻を付ける
+    let dir = std::env::temp_dir().join(format!("gpx_to_map_select_tracks_{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let route = r#"<?xml version="1.0"?>
+<gpx version="1.1" creator="test" xmlns="http://www.topografix.com/GPX/1/1"><rte>
+<rtept lat="35.0" lon="139.5"></rtept><rtept lat="35.01" lon="139.5"></rtept>
+</rte></gpx>"#;
+    let paths: Vec<String> = ["a.gpx", "b.gpx"]
+        .iter()
+        .map(|name| {
+            let path = dir.join(name);
+            fs::write(&path, route).unwrap();
+            path.to_string_lossy().to_string()
+        })
+        .collect();
+    let synthesis = TimeSynthesis {
+        speed: 20.0,
+        model: crate::synthesis::SpeedModel::Constant,
+        start: Some(Utc.ymd(2020, 8, 1).and_hms(0, 0, 0)),
+    };
+    let file = TrackFile::read_files(&paths, Some(&synthesis), None).unwrap();
+    let (first, second) = (file.tracks[0].time_range().unwrap(), file.tracks[1].time_range().unwrap());
+    assert_eq!(second.0, first.1);
+    assert!(second.1 > second.0);
+    fs::remove_dir_all(&dir).unwrap();
+}
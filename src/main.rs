@@ -2,69 +2,270 @@
 // https://icon-rainbow.com/
 // https://qiita.com/tasshi/items/de36d9add14f24317f47
 
+mod adaptive;
 mod arguments;
+mod cleaning;
+mod climbs;
+mod dem;
+mod elevation;
+mod export;
+mod extensions;
+mod igc;
+mod info;
+mod laps;
+mod lint;
+mod loader;
 mod map_image;
+mod overlay;
+mod privacy;
+mod segments;
+mod smoothing;
+mod srt;
+mod stats;
+mod stops;
+mod synthesis;
 mod track_point;
+mod trim;
 
 use anyhow::Result;
-use arguments::Opts;
-use chrono::{DateTime, Utc};
+use arguments::{LintOpts, Opts, StatsOpts, SubCommand};
+use chrono::{Duration, Utc};
+use chrono_tz::Tz;
 use clap::Clap;
 use globalmaptiles::GlobalMercator;
 use image::{imageops, DynamicImage};
-use map_image::MapBaseImage;
-use std::{fs, fs::File, io::{BufReader, Write}, process::{Child, Command, Stdio}, sync::Arc, sync::Mutex, sync::mpsc::{self, Receiver, Sender}, thread};
-use track_point::{GroupIterater, TrackIter};
+use map_image::{MapBaseImage, TileCache};
+use loader::TrackFile;
+use std::{fs, fs::File, io::Write, path::Path, process::{Child, Command, Stdio}, sync::Arc, sync::Mutex, sync::mpsc::{self, Receiver, Sender}, thread};
+use elevation::ClimbProfile;
+use overlay::GapIndicator;
+use privacy::Privacy;
+use track_point::{Frame, FrameEvent, GroupIterater, TrackIter, TrackLog, TrackPoint, Waypoint};
 use tokio::{task::JoinHandle};
 // const OPENSTREAT_MAP_URL: &str = "https://tile.openstreetmap.org/";
 
 const ASSET_CYCLE_ICON: &str = "assets/cycle.png";
+// 動画のフレームレート
+const FPS: usize = 30;
+// ラップの通知を表示する秒数
+const SPLIT_NOTIFY_SECONDS: usize = 3;
+// 登り口のバナーを表示する秒数
+const CLIMB_NOTIFY_SECONDS: usize = 5;
+// セグメントの結果を表示する秒数
+const SEGMENT_NOTIFY_SECONDS: usize = 5;
+
+type FrameSender = Sender<Mutex<Option<DynamicImage>>>;
+type FrameReceiver = Receiver<Mutex<Option<DynamicImage>>>;
+
+// 地図画像の生成に必要な情報
+struct RenderContext {
+    zoom: u32,
+    map_image_size: u32,
+    tile_dir: String,
+    tile_cache: TileCache,
+    waypoints: Vec<Waypoint>,
+    gap_indicator: GapIndicator,
+    climb_profile: Option<ClimbProfile>,
+    // 山頂のマーカーを描く登り
+    climbs: Vec<climbs::Climb>,
+    privacy: Privacy,
+}
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let opts: Opts = Opts::parse();
 
-    gpx_to_map_movie(
-        &opts.gpx_file,
-        &opts.dest_file,
-        opts.get_start_date(),
-        opts.get_end_date(),
-        opts.map_image_size,
-        opts.zoom,
-        &opts.tile_dir,
-    )
-    .await
+    if let Some(SubCommand::Info(info_opts)) = &opts.command {
+        for path in loader::expand_paths(&info_opts.files)? {
+            let file = TrackFile::read(&path, None, opts.get_timezone()?)?;
+            info::print_info(&path, &file, opts.get_timezone()?);
+        }
+        return Ok(());
+    }
+
+    if let Some(SubCommand::Lint(lint_opts)) = &opts.command {
+        return lint_files(lint_opts, opts.get_timezone()?);
+    }
+
+    if let Some(SubCommand::Stats(stats_opts)) = &opts.command {
+        return print_stats(stats_opts, &opts);
+    }
+
+    let input_files = opts.get_input_files()?;
+    let dest_file = opts.get_dest_file();
+    let file = TrackFile::read_files(&input_files, opts.get_synthesis()?.as_ref(), opts.get_timezone()?)?;
+
+    // DJIのSRTは元の動画の1フレームごとの位置なので、フレームレートが違うと元の動画と重ねたときにずれます
+    for path in input_files.iter().filter(|path| path.to_lowercase().ends_with(".srt")) {
+        let track = &TrackFile::read(path, None, opts.get_timezone()?)?.tracks[0];
+        if let Some(rate) = srt::frame_rate(track).filter(|rate| (rate - FPS as f64).abs() > 0.5) {
+            println!("{} のフレームレート(約{:.1}fps)は出力する動画の{}fpsと異なります", path, rate, FPS);
+        }
+    }
+
+    // トラックごとに出力する場合は、ファイル名に番号を付けます
+    let targets: Vec<(TrackLog, String, Option<String>)> = if opts.each_track {
+        (0..file.tracks.len())
+            .filter_map(|index| {
+                let track = file.select(&loader::TrackSelection::Index(index)).ok()?;
+                let export_file = opts.export.as_ref().map(|path| numbered_file_name(path, index));
+                Some((track, numbered_file_name(&dest_file, index), export_file))
+            })
+            .collect()
+    } else {
+        let selection = opts.get_track_selection(input_files.len());
+        vec![(file.select(&selection)?, dest_file.clone(), opts.export.clone())]
+    };
+
+    for (track, dest_file, export_file) in targets {
+        let track = process_track(track, &opts, true)?;
+        if let Some(export_file) = export_file {
+            export_track(&track, &export_file, &opts)?;
+        }
+        if !opts.export_only {
+            // 動画を出力できた場合だけ、セグメントの走行を記録に追加します
+            let efforts = gpx_to_map_movie(&track, &dest_file, &opts).await?;
+            record_efforts(&efforts, &opts)?;
+        }
+    }
+
+    Ok(())
 }
 
-async fn gpx_to_map_movie(
-    gpx_file: &str,
-    dest_path: &str,
-    start_date: Option<DateTime<Utc>>,
-    end_date: Option<DateTime<Utc>>,
-    map_image_size: u32,
-    zoom: u32,
-    tile_dir: &str,
-) -> Result<()> {
-    let f = File::open(gpx_file)?;
-    let reader = BufReader::new(f);
+// ファイルごとに検査結果を表示し、指定があれば修正したgpxを出力します
+fn lint_files(lint_opts: &LintOpts, tz: Option<Tz>) -> Result<()> {
+    let paths = loader::expand_paths(&lint_opts.files)?;
+    if lint_opts.fix.is_some() && paths.len() != 1 {
+        return Err(anyhow::anyhow!("修正したgpxを出力する場合は入力ファイルを1つにしてください"));
+    }
 
-    let gpx = gpx::read(reader).map_err(|x| anyhow::anyhow!(x.description().to_string()))?;
-    let track = gpx
-        .tracks
-        .first()
-        .ok_or(anyhow::anyhow!("データがみつかりません"))?;
+    for path in &paths {
+        let mut gpx = TrackFile::read_raw_gpx(path, tz)?;
+        let issues = lint::lint(&gpx, Duration::seconds(lint_opts.max_gap), Utc::now());
+
+        println!("{}", path);
+        for issue in &issues {
+            println!("    {}", issue);
+        }
+        println!("問題: {} 件", issues.len());
+
+        if let Some(dest) = &lint_opts.fix {
+            let count = lint::repair(&mut gpx);
+            if gpx.version == gpx::GpxVersion::Unknown {
+                gpx.version = gpx::GpxVersion::Gpx11;
+            }
+            gpx::write(&gpx, File::create(dest)?).map_err(|x| anyhow::anyhow!(x.to_string()))?;
+            println!("{} ポイントを修正して {} に出力しました(拡張データは出力されません)", count, dest);
+        }
+    }
+
+    Ok(())
+}
+
+// 描画前のトラックの加工
+// verbose ならクリーニングや標高の補正の結果を表示します
+fn process_track(mut track: TrackLog, opts: &Opts, verbose: bool) -> Result<TrackLog> {
+    // 時刻の逆行の扱い(以降の処理はすべて時刻順のポイントを前提にします)
+    track.order_by_time(opts.time_order)?;
+
+    // おかしなポイントの削除
+    if opts.clean {
+        let report = cleaning::clean_track(&mut track, &opts.get_clean_config());
+        if verbose {
+            println!("クリーニング: {}", report);
+        }
+    }
+
+    // DEMによる標高の補正
+    if let Some(dir) = &opts.dem {
+        let weight = opts.get_dem_weight()?;
+        let mut dem = dem::Dem::open(dir)?;
+        let count = dem::correct_track(&mut track, &mut dem, weight);
+        let total: usize = track.segments.iter().map(|segment| segment.len()).sum();
+        if verbose {
+            println!("DEMで標高を補正しました: {} / {} ポイント", count, total);
+        }
+    }
+
+    // 位置の平滑化
+    if opts.smoothing == smoothing::Smoothing::Kalman {
+        smoothing::kalman_track(&mut track, opts.smoothing_noise);
+    }
+
+    elevation::smooth_track(&mut track, opts.ele_filter, opts.get_ele_window());
+
+    Ok(track)
+}
+
+// 描画したセグメントの走行を返します
+async fn gpx_to_map_movie(track: &TrackLog, dest_path: &str, opts: &Opts) -> Result<Vec<segments::Effort>> {
+    let map_image_size = opts.map_image_size;
+    let tile_dir = &opts.tile_dir;
 
     // let giter = GroupIterater::new(TrackIter::get_iter(track, 30, start_date, end_date), 24);
-    let iter = TrackIter::get_iter(track, 30, start_date, end_date);
+    let iter = build_iter(track, opts, true)?;
+
+    if iter.is_by_distance() {
+        println!(
+            "再生速度: 動画1秒あたり{:.0}m (動画の長さ 約{:.0}秒)",
+            iter.speed(),
+            iter.video_length()
+        );
+    } else {
+        println!(
+            "再生速度: {:.1}倍 (動画の長さ 約{:.0}秒)",
+            iter.speed(),
+            iter.video_length()
+        );
+    }
 
     // ディレクトリ作成
     fs::create_dir_all(&tile_dir)?; //タイルディレクトリ
 
     // 通信用チャンネル作成
-    let (tx, rx):(Sender<Mutex<Option<DynamicImage>>>, Receiver<Mutex<Option<DynamicImage>>>) = mpsc::channel();
+    let (tx, rx): (FrameSender, FrameReceiver) = mpsc::channel();
+
+    // ラップと登り(描画する範囲のトラックで検出します)
+    let (start_date, end_date) = opts.get_time_range(track)?;
+    let clipped = export::clip(track, start_date, end_date);
+    let lap_mode = opts.get_lap_mode()?;
+    let splits = if lap_mode == laps::LapMode::None {
+        Vec::new()
+    } else {
+        laps::detect_splits(&clipped, &lap_mode)
+    };
+    for split in &splits {
+        println!("{}", split);
+    }
+    let climbs = if opts.climbs {
+        climbs::detect_climbs(&clipped)
+    } else {
+        Vec::new()
+    };
+    for climb in &climbs {
+        println!("{}", climb);
+    }
+    let efforts = match_segments(&clipped, opts)?;
+    for effort in &efforts {
+        println!("{}", effort);
+    }
 
-    // タイルのキャッシュを取得
-    let tile_cache:Arc<Mutex<Vec<(i32, i32, DynamicImage)>>> = Arc::new(Mutex::new(Vec::new()));
+    // 地図画像生成用の情報(タイルのキャッシュを含む)
+    let context = Arc::new(RenderContext {
+        zoom: opts.zoom,
+        map_image_size,
+        tile_dir: tile_dir.to_string(),
+        tile_cache: Arc::new(Mutex::new(Vec::new())),
+        waypoints: track.waypoints.clone(),
+        gap_indicator: opts.gap_indicator,
+        climb_profile: if opts.show_elevation {
+            Some(ClimbProfile::new(track, opts.ele_threshold))
+        } else {
+            None
+        },
+        climbs: climbs.clone(),
+        privacy: opts.get_privacy()?,
+    });
 
     // 出力用スレッド生成
     let dest_path = dest_path.to_string();
@@ -90,32 +291,40 @@ async fn gpx_to_map_movie(
 
     });
     
+    // ラップの終わりと登り口の通知
+    let mut split_notifier = overlay::Notifier::new(
+        splits.iter().map(|split| (split.end, *split)).collect(),
+        SPLIT_NOTIFY_SECONDS * FPS,
+    );
+    let mut climb_notifier = overlay::Notifier::new(
+        climbs.iter().map(|climb| (climb.start, *climb)).collect(),
+        CLIMB_NOTIFY_SECONDS * FPS,
+    );
+    let rendered_efforts: Vec<segments::Effort> = efforts.iter().map(|effort| effort.effort.clone()).collect();
+    let mut segment_timer = segments::SegmentTimer::new(efforts, &clipped, SEGMENT_NOTIFY_SECONDS * FPS);
+
     let giter = GroupIterater::new(iter, 6);
 
+    // プライバシーゾーン内では、地図をゾーンに入る前の位置で止めます
+    let mut view: Option<(f64, f64)> = None;
+
 
     //for point in iter {
     for group_items in giter {
-        let mut tasks :Vec<Box<JoinHandle<Result<DynamicImage>>>> = Vec::new();
-
-        for point in group_items {
+        let mut tasks: Vec<JoinHandle<Result<DynamicImage>>> = Vec::new();
+
+        for frame in group_items {
+            let frame = frame?;
+            let split = split_notifier.update(frame.point.time);
+            let climb = climb_notifier.update(frame.point.time);
+            let segment = segment_timer.update(frame.point.time);
+            if view.is_none() || !context.privacy.contains(&frame.point) {
+                view = Some((frame.point.lat, frame.point.lng));
+            }
+            let future = make_map_image(frame, view.unwrap(), split, climb, segment, context.clone());
 
-            let (tile_x, tile_y, pixel_x, pixel_y, pixel_size) =
-                calc_tile_and_pixel(point.lat, point.lng, zoom);
-    
-            let future = make_map_image(
-                zoom,
-                tile_x,
-                tile_y,
-                pixel_x,
-                pixel_y,
-                pixel_size,
-                map_image_size,
-                tile_dir.to_string(),
-                tile_cache.clone(),
-            );
-            
             let x = tokio::task::spawn(future);
-            tasks.push(Box::new(x));
+            tasks.push(x);
         }
 
         for task in tasks {
@@ -137,17 +346,171 @@ async fn gpx_to_map_movie(
     handle.join().expect("出力処理でエラーが発生しました");
 
 
+    Ok(rendered_efforts)
+}
+
+// 描画と同じ条件でフレームを生成するイテレータを作成します(verbose なら停止区間を表示します)
+fn build_iter(track: &TrackLog, opts: &Opts, verbose: bool) -> Result<TrackIter> {
+    let (start_date, end_date) = opts.get_time_range(track)?;
+    let iter = TrackIter::get_iter(track, FPS, start_date, end_date)
+        .with_gap(opts.gap_mode, opts.get_max_gap())
+        .with_spline(opts.smoothing == smoothing::Smoothing::Spline);
+
+    // 区間の様子に合わせた再生速度
+    let iter = if opts.adaptive {
+        iter.with_speed_profile(adaptive::SpeedProfile::new(track, &opts.get_adaptive_config()))
+    } else {
+        iter
+    };
+
+    // 停止区間の検出
+    let iter = if opts.stops != stops::StopMode::None {
+        let stops = stops::detect_stops(track, opts.stop_speed, opts.get_stop_time());
+        if verbose {
+            for stop in &stops {
+                println!(
+                    "停止: {} から {}分 ({:.5}, {:.5})",
+                    arguments::to_local(stop.start, opts.get_timezone()?).format("%H:%M:%S"),
+                    stop.duration().num_minutes(),
+                    stop.point.lat,
+                    stop.point.lng
+                );
+            }
+        }
+        iter.with_stops(stops, opts.stops, opts.stop_speedup)
+    } else {
+        iter
+    };
+
+    // 長さに合わせる場合は停止区間と区間ごとの倍率を含めて速度を決めます
+    Ok(iter.with_playback(opts.get_playback()?))
+}
+
+// 描画と同じ読み込み・処理・切り出しをしたトラックの統計を表示します
+fn print_stats(stats_opts: &StatsOpts, opts: &Opts) -> Result<()> {
+    let paths = loader::expand_paths(&stats_opts.files)?;
+    let file = TrackFile::read_files(&paths, opts.get_synthesis()?.as_ref(), opts.get_timezone()?)?;
+    let track = file.select(&opts.get_track_selection(paths.len()))?;
+    let track = process_track(track, opts, !stats_opts.json)?;
+    let (start_date, end_date) = opts.get_time_range(&track)?;
+    let track = export::clip(&track, start_date, end_date);
+
+    let stats = stats::Stats::new(&track, &opts.get_stats_config());
+    let climbs = climbs::detect_climbs(&track);
+    let efforts = match_segments(&track, opts)?;
+    let splits = if stats_opts.splits {
+        laps::detect_splits(&track, &opts.get_lap_mode()?)
+    } else {
+        Vec::new()
+    };
+
+    if stats_opts.json {
+        let mut json = stats.to_json();
+        json["climbs"] = climbs.iter().map(|climb| climb.to_json()).collect();
+        if opts.segments.is_some() {
+            json["efforts"] = efforts.iter().map(|effort| effort.to_json()).collect();
+        }
+        if stats_opts.splits {
+            json["splits"] = splits
+                .iter()
+                .map(|split| {
+                    serde_json::json!({
+                        "number": split.number,
+                        "start": split.start.to_rfc3339(),
+                        "end": split.end.to_rfc3339(),
+                        "distance_m": split.distance,
+                        "duration_s": split.duration().num_seconds(),
+                        "avg_speed_kmh": split.avg_speed(),
+                    })
+                })
+                .collect();
+        }
+        println!("{}", serde_json::to_string_pretty(&json)?);
+    } else {
+        print!("{}", stats);
+        for climb in &climbs {
+            println!("{}", climb);
+        }
+        for effort in &efforts {
+            println!("{}", effort);
+        }
+        for split in &splits {
+            println!("{}", split);
+        }
+    }
+    Ok(())
+}
+
+// セグメントの走行を探し、記録と比べて順位を付けます
+fn match_segments(track: &TrackLog, opts: &Opts) -> Result<Vec<segments::RankedEffort>> {
+    let segments = opts.get_segments()?;
+    if segments.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let mut efforts: Vec<segments::Effort> = segments.iter().flat_map(|segment| segment.efforts(track)).collect();
+    efforts.sort_by_key(|effort| effort.start);
+
+    let history = segments::History::load(&opts.segment_history)?;
+    Ok(efforts.iter().map(|effort| history.rank(effort)).collect())
+}
+
+// セグメントの走行を記録に追加します
+fn record_efforts(efforts: &[segments::Effort], opts: &Opts) -> Result<()> {
+    if efforts.is_empty() {
+        return Ok(());
+    }
+
+    let mut history = segments::History::load(&opts.segment_history)?;
+    history.record(efforts);
+    history.save(&opts.segment_history)
+}
+
+// 処理したトラック(指定があればフレームごとの位置)をファイルに出力します
+fn export_track(track: &TrackLog, path: &str, opts: &Opts) -> Result<()> {
+    let mut exported = if opts.export_frames {
+        let points = build_iter(track, opts, opts.export_only)?
+            .map(|frame| frame.map(|frame| frame.point))
+            .collect::<Result<Vec<TrackPoint>>>()?;
+        TrackLog {
+            segments: vec![points],
+            ..track.clone()
+        }
+    } else {
+        let (start_date, end_date) = opts.get_time_range(track)?;
+        export::clip(track, start_date, end_date)
+    };
+
+    // プライバシーゾーン内のポイント(フレーム)とウェイポイントは出力しません
+    opts.get_privacy()?.remove_from(&mut exported);
+
+    export::export(&exported, path)?;
+    let count: usize = exported.segments.iter().map(|segment| segment.len()).sum();
+    println!("{} ポイントを {} に出力しました", count, path);
     Ok(())
 }
 
+// dest.mp4 -> dest_0.mp4
+fn numbered_file_name(path: &str, index: usize) -> String {
+    let path = Path::new(path);
+    let stem = path.file_stem().and_then(|x| x.to_str()).unwrap_or("dest");
+    let file_name = match path.extension().and_then(|x| x.to_str()) {
+        Some(extension) => format!("{}_{}.{}", stem, index, extension),
+        None => format!("{}_{}", stem, index),
+    };
+
+    path.with_file_name(file_name).to_string_lossy().to_string()
+}
+
 fn make_ffmpeg_process(image_size: u32, outfile: &str) -> Result<Child> {
     let cmd = "ffmpeg";
     let size_text = format!("{}x{}", image_size, image_size);
+    let fps_text = FPS.to_string();
     let mut cmd = Command::new(cmd);
     let cmd = cmd
         .args(&[
             "-framerate",
-            "30",
+            &fps_text,
             "-f",
             "rawvideo",
             "-pix_fmt",
@@ -170,20 +533,24 @@ fn make_ffmpeg_process(image_size: u32, outfile: &str) -> Result<Child> {
     Ok(cmd.spawn()?)
 }
 
-async fn make_map_image<'a>(
-    zoom: u32,
-    tile_x: i32,
-    tile_y: i32,
-    pixel_x: i32,
-    pixel_y: i32,
-    tile_size: u32,
-    map_image_size: u32,
-    tile_dir: String,
-    tile_cache: Arc<Mutex<Vec<(i32, i32, DynamicImage)>>>,
+async fn make_map_image(
+    frame: Frame,
+    view: (f64, f64),
+    split: Option<laps::Split>,
+    climb: Option<climbs::Climb>,
+    segment: Option<String>,
+    context: Arc<RenderContext>,
 ) -> Result<DynamicImage> {
+    let point = frame.point;
+    // 地図の中心(プライバシーゾーン内では現在地と異なります)
+    let (view_lat, view_lng) = view;
+    let zoom = context.zoom;
+    let map_image_size = context.map_image_size;
+    let (tile_x, tile_y, pixel_x, pixel_y, tile_size) =
+        calc_tile_and_pixel(view_lat, view_lng, zoom);
+
+    let mut image_store = MapBaseImage::new(&context.tile_dir, &context.tile_cache);
 
-    let mut image_store = MapBaseImage::new(&tile_dir, &tile_cache);
-        
     // 必要なタイル数を計算
     let tile_calc = (map_image_size - 1) / tile_size + 1;
 
@@ -205,6 +572,54 @@ async fn make_map_image<'a>(
     );
     let mut img = dest_image.to_image();
 
+    // プライバシーゾーン内の地図を隠します
+    if !context.privacy.zones.is_empty() {
+        let to_pixel = |lat: f64, lng: f64| {
+            let (offset_x, offset_y) = calc_pixel_offset(view_lat, view_lng, lat, lng, zoom);
+            ((map_image_size / 2) as f64 + offset_x as f64, (map_image_size / 2) as f64 + offset_y as f64)
+        };
+        let meters_per_pixel = GlobalMercator::default().resolution(zoom) * view_lat.to_radians().cos();
+        context.privacy.apply(&mut img, to_pixel, meters_per_pixel);
+    }
+
+    // ウェイポイント描画(プライバシーゾーン内のものは描きません)
+    for waypoint in &context.waypoints {
+        if context.privacy.contains_waypoint(waypoint) {
+            continue;
+        }
+        let (offset_x, offset_y) =
+            calc_pixel_offset(view_lat, view_lng, waypoint.lat, waypoint.lng, zoom);
+        overlay::draw_marker(
+            &mut img,
+            (map_image_size / 2) as i32 + offset_x,
+            (map_image_size / 2) as i32 + offset_y,
+            (map_image_size / 80).max(2) as i32,
+            overlay::WAYPOINT_COLOR,
+        );
+    }
+
+    let label_scale = (map_image_size / 200).max(1);
+
+    // 山頂のマーカーと登りの分類(プライバシーゾーン内のものは描きません)
+    for summit in &context.climbs {
+        let (lat, lng) = summit.summit;
+        if context.privacy.contains(&TrackPoint::new(point.time, lat, lng)) {
+            continue;
+        }
+        let (offset_x, offset_y) = calc_pixel_offset(view_lat, view_lng, lat, lng, zoom);
+        let (x, y) = ((map_image_size / 2) as i32 + offset_x, (map_image_size / 2) as i32 + offset_y);
+        let radius = (map_image_size / 60).max(3) as i32;
+        overlay::draw_marker(&mut img, x, y, radius, overlay::SUMMIT_COLOR);
+        let (_, height) = overlay::text_size(summit.category.label(), label_scale);
+        overlay::draw_label(
+            &mut img,
+            summit.category.label(),
+            x,
+            y - radius * 2 - height as i32,
+            label_scale,
+        );
+    }
+
     // 自転車アイコン付与
     let mut icon_path = std::env::current_exe()?
         .parent()
@@ -215,18 +630,100 @@ async fn make_map_image<'a>(
     }
 
     let cycle_img = image::open(icon_path)?.to_rgba();
-    let cycle_img = imageops::resize(
+    let mut cycle_img = imageops::resize(
         &cycle_img,
         map_image_size / 20,
         map_image_size / 20,
         imageops::FilterType::Triangle,
     );
-    imageops::overlay(
-        &mut img,
-        &cycle_img,
-        map_image_size / 2 - map_image_size / 40,
-        map_image_size / 2 - map_image_size / 40,
-    );
+
+    // 標高・獲得標高の表示
+    if let (Some(profile), Some(ele)) = (&context.climb_profile, point.ele) {
+        let text = format!("{:.0}M +{:.0}M", ele, profile.ascent_at(point.time));
+        let (width, height) = overlay::text_size(&text, label_scale);
+        overlay::draw_label(
+            &mut img,
+            &text,
+            (width / 2 + label_scale * 4) as i32,
+            (map_image_size - height - label_scale * 4) as i32,
+            label_scale,
+        );
+    }
+
+    // 停止区間のカード表示
+    if let Some(FrameEvent::Paused { duration, .. }) = frame.event {
+        overlay::draw_label(
+            &mut img,
+            &stops::pause_text(duration),
+            (map_image_size / 2) as i32,
+            (map_image_size * 2 / 3) as i32,
+            label_scale * 2,
+        );
+    }
+
+    // ラップの通知
+    if let Some(split) = split {
+        overlay::draw_label(
+            &mut img,
+            &split.label(),
+            (map_image_size / 2) as i32,
+            (map_image_size / 8) as i32,
+            label_scale,
+        );
+    }
+
+    // 登り口のバナー(ラップの通知の下)
+    if let Some(climb) = climb {
+        overlay::draw_label(
+            &mut img,
+            &climb.label(),
+            (map_image_size / 2) as i32,
+            (map_image_size / 8 + label_scale * 14) as i32,
+            label_scale,
+        );
+    }
+
+    // セグメントのタイムとベストとの差(登り口のバナーの下)
+    if let Some(segment) = segment {
+        overlay::draw_label(
+            &mut img,
+            &segment,
+            (map_image_size / 2) as i32,
+            (map_image_size / 8 + label_scale * 28) as i32,
+            label_scale,
+        );
+    }
+
+    // 信号途絶中の表示
+    if let Some(FrameEvent::SignalLost { progress }) = frame.event {
+        match context.gap_indicator {
+            GapIndicator::Text => overlay::draw_label(
+                &mut img,
+                "SIGNAL LOST",
+                (map_image_size / 2) as i32,
+                (map_image_size / 20) as i32,
+                label_scale,
+            ),
+            GapIndicator::Fade => {
+                // 途絶直後から徐々にアイコンを薄くします
+                let alpha = 1.0 - 0.7 * (progress * 4.0).min(1.0);
+                for pixel in cycle_img.pixels_mut() {
+                    pixel[3] = (pixel[3] as f64 * alpha) as u8;
+                }
+            }
+            GapIndicator::None => {}
+        }
+    }
+
+    // プライバシーゾーン内ではアイコンを隠します
+    if !context.privacy.contains(&point) {
+        imageops::overlay(
+            &mut img,
+            &cycle_img,
+            map_image_size / 2 - map_image_size / 40,
+            map_image_size / 2 - map_image_size / 40,
+        );
+    }
 
     let img = DynamicImage::ImageRgba8(img);
 
@@ -250,3 +747,16 @@ fn calc_tile_and_pixel(lat: f64, lng: f64, zoom: u32) -> (i32, i32, i32, i32, u3
     // 結果をタプルにして返します
     (tile_x, tile_y, pixel_x, pixel_y, t.tile_size())
 }
+
+// 中心の位置から見た対象の位置(ピクセル単位)を計算します
+fn calc_pixel_offset(lat: f64, lng: f64, target_lat: f64, target_lng: f64, zoom: u32) -> (i32, i32) {
+    let (tile_x, tile_y, pixel_x, pixel_y, tile_size) = calc_tile_and_pixel(lat, lng, zoom);
+    let (target_tile_x, target_tile_y, target_pixel_x, target_pixel_y, _) =
+        calc_tile_and_pixel(target_lat, target_lng, zoom);
+
+    let tile_size = tile_size as i32;
+    (
+        (target_tile_x - tile_x) * tile_size + target_pixel_x - pixel_x,
+        (target_tile_y - tile_y) * tile_size + target_pixel_y - pixel_y,
+    )
+}
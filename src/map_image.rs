@@ -4,14 +4,16 @@ use std::{fs::File, io::BufWriter, io::Write, ops::Range, path::Path, path::Path
 
 const JAPAN_MAP_URL: &str = "https://cyberjapandata.gsi.go.jp/xyz/std/";
 
+pub type TileCache = Arc<Mutex<Vec<(i32, i32, DynamicImage)>>>;
+
 pub struct MapBaseImage<'a> {
     max_store: usize,
     tile_dir: &'a str,
-    cache: &'a Arc<Mutex<Vec<(i32, i32, DynamicImage)>>>,
+    cache: &'a TileCache,
 }
 
 impl<'a> MapBaseImage<'a> {
-    pub fn new(tile_dir: &'a str, cache: &'a Arc<Mutex<Vec<(i32, i32, DynamicImage)>>>) -> Self {
+    pub fn new(tile_dir: &'a str, cache: &'a TileCache) -> Self {
         Self {
             max_store: 10,
             tile_dir,
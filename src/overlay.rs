@@ -0,0 +1,200 @@
+// 地図画像への描き込み
+use chrono::{DateTime, Utc};
+use image::{Rgba, RgbaImage};
+use std::str::FromStr;
+
+pub const WAYPOINT_COLOR: Rgba<u8> = Rgba([220, 40, 40, 255]);
+pub const SUMMIT_COLOR: Rgba<u8> = Rgba([40, 150, 60, 255]);
+const BORDER_COLOR: Rgba<u8> = Rgba([255, 255, 255, 255]);
+const TEXT_COLOR: Rgba<u8> = Rgba([255, 255, 255, 255]);
+const LABEL_BACKGROUND: Rgba<u8> = Rgba([0, 0, 0, 160]);
+
+// 信号途絶中の表示方法
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GapIndicator {
+    // "SIGNAL LOST" と表示します
+    Text,
+    // アイコンを薄くします
+    Fade,
+    None,
+}
+
+impl FromStr for GapIndicator {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(GapIndicator::Text),
+            "fade" => Ok(GapIndicator::Fade),
+            "none" => Ok(GapIndicator::None),
+            _ => Err(anyhow::anyhow!("信号途絶の表示は text, fade, none のいずれかです")),
+        }
+    }
+}
+
+// 動画でラップの終わりや登り口などを通過したときに、一定のフレーム数だけ通知を表示します
+pub struct Notifier<T> {
+    // 通知する時刻の順
+    events: Vec<(DateTime<Utc>, T)>,
+    next: usize,
+    frames: usize,
+    current: Option<(T, usize)>,
+}
+
+impl<T: Copy> Notifier<T> {
+    pub fn new(events: Vec<(DateTime<Utc>, T)>, frames: usize) -> Self {
+        Self {
+            events,
+            next: 0,
+            frames,
+            current: None,
+        }
+    }
+
+    // フレームの時刻に表示する通知(フレームの順に呼び出します)
+    pub fn update(&mut self, time: DateTime<Utc>) -> Option<T> {
+        while self.next < self.events.len() && self.events[self.next].0 <= time {
+            self.current = Some((self.events[self.next].1, self.frames));
+            self.next += 1;
+        }
+
+        let (event, remaining) = self.current?;
+        self.current = if remaining > 1 { Some((event, remaining - 1)) } else { None };
+        Some(event)
+    }
+}
+
+// 5x7ドットのフォント(各行の下位5ビットを左から使います)
+const FONT: [(char, [u8; 7]); 47] = [
+    ('0', [0x0E, 0x11, 0x13, 0x15, 0x19, 0x11, 0x0E]),
+    ('1', [0x04, 0x0C, 0x04, 0x04, 0x04, 0x04, 0x0E]),
+    ('2', [0x0E, 0x11, 0x01, 0x02, 0x04, 0x08, 0x1F]),
+    ('3', [0x1F, 0x02, 0x04, 0x02, 0x01, 0x11, 0x0E]),
+    ('4', [0x02, 0x06, 0x0A, 0x12, 0x1F, 0x02, 0x02]),
+    ('5', [0x1F, 0x10, 0x1E, 0x01, 0x01, 0x11, 0x0E]),
+    ('6', [0x06, 0x08, 0x10, 0x1E, 0x11, 0x11, 0x0E]),
+    ('7', [0x1F, 0x01, 0x02, 0x04, 0x08, 0x08, 0x08]),
+    ('8', [0x0E, 0x11, 0x11, 0x0E, 0x11, 0x11, 0x0E]),
+    ('9', [0x0E, 0x11, 0x11, 0x0F, 0x01, 0x02, 0x0C]),
+    ('A', [0x0E, 0x11, 0x11, 0x11, 0x1F, 0x11, 0x11]),
+    ('B', [0x1E, 0x11, 0x11, 0x1E, 0x11, 0x11, 0x1E]),
+    ('C', [0x0E, 0x11, 0x10, 0x10, 0x10, 0x11, 0x0E]),
+    ('D', [0x1C, 0x12, 0x11, 0x11, 0x11, 0x12, 0x1C]),
+    ('E', [0x1F, 0x10, 0x10, 0x1E, 0x10, 0x10, 0x1F]),
+    ('F', [0x1F, 0x10, 0x10, 0x1E, 0x10, 0x10, 0x10]),
+    ('G', [0x0E, 0x11, 0x10, 0x17, 0x11, 0x11, 0x0F]),
+    ('H', [0x11, 0x11, 0x11, 0x1F, 0x11, 0x11, 0x11]),
+    ('I', [0x0E, 0x04, 0x04, 0x04, 0x04, 0x04, 0x0E]),
+    ('J', [0x07, 0x02, 0x02, 0x02, 0x02, 0x12, 0x0C]),
+    ('K', [0x11, 0x12, 0x14, 0x18, 0x14, 0x12, 0x11]),
+    ('L', [0x10, 0x10, 0x10, 0x10, 0x10, 0x10, 0x1F]),
+    ('M', [0x11, 0x1B, 0x15, 0x15, 0x11, 0x11, 0x11]),
+    ('N', [0x11, 0x11, 0x19, 0x15, 0x13, 0x11, 0x11]),
+    ('O', [0x0E, 0x11, 0x11, 0x11, 0x11, 0x11, 0x0E]),
+    ('P', [0x1E, 0x11, 0x11, 0x1E, 0x10, 0x10, 0x10]),
+    ('Q', [0x0E, 0x11, 0x11, 0x11, 0x15, 0x12, 0x0D]),
+    ('R', [0x1E, 0x11, 0x11, 0x1E, 0x14, 0x12, 0x11]),
+    ('S', [0x0F, 0x10, 0x10, 0x0E, 0x01, 0x01, 0x1E]),
+    ('T', [0x1F, 0x04, 0x04, 0x04, 0x04, 0x04, 0x04]),
+    ('U', [0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x0E]),
+    ('V', [0x11, 0x11, 0x11, 0x11, 0x11, 0x0A, 0x04]),
+    ('W', [0x11, 0x11, 0x11, 0x15, 0x15, 0x15, 0x0A]),
+    ('X', [0x11, 0x11, 0x0A, 0x04, 0x0A, 0x11, 0x11]),
+    ('Y', [0x11, 0x11, 0x11, 0x0A, 0x04, 0x04, 0x04]),
+    ('Z', [0x1F, 0x01, 0x02, 0x04, 0x08, 0x10, 0x1F]),
+    (' ', [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]),
+    (':', [0x00, 0x0C, 0x0C, 0x00, 0x0C, 0x0C, 0x00]),
+    ('.', [0x00, 0x00, 0x00, 0x00, 0x00, 0x0C, 0x0C]),
+    (',', [0x00, 0x00, 0x00, 0x00, 0x0C, 0x04, 0x08]),
+    ('-', [0x00, 0x00, 0x00, 0x1F, 0x00, 0x00, 0x00]),
+    ('+', [0x00, 0x04, 0x04, 0x1F, 0x04, 0x04, 0x00]),
+    ('/', [0x00, 0x01, 0x02, 0x04, 0x08, 0x10, 0x00]),
+    ('%', [0x18, 0x19, 0x02, 0x04, 0x08, 0x13, 0x03]),
+    ('(', [0x02, 0x04, 0x08, 0x08, 0x08, 0x04, 0x02]),
+    (')', [0x08, 0x04, 0x02, 0x02, 0x02, 0x04, 0x08]),
+    ('#', [0x0A, 0x0A, 0x1F, 0x0A, 0x1F, 0x0A, 0x0A]),
+];
+
+// 半透明の色を重ねます
+fn blend_pixel(img: &mut RgbaImage, x: i32, y: i32, color: Rgba<u8>) {
+    if x < 0 || y < 0 || x >= img.width() as i32 || y >= img.height() as i32 {
+        return;
+    }
+
+    let pixel = img.get_pixel_mut(x as u32, y as u32);
+    let alpha = color[3] as f64 / 255.0;
+    for i in 0..3 {
+        pixel[i] = (pixel[i] as f64 * (1.0 - alpha) + color[i] as f64 * alpha).round() as u8;
+    }
+    pixel[3] = pixel[3].max(color[3]);
+}
+
+// (x, y)を中心に円形のマーカーを描画します(画像外の部分は描画しません)
+pub fn draw_marker(img: &mut RgbaImage, x: i32, y: i32, radius: i32, color: Rgba<u8>) {
+    let border = radius + (radius / 3).max(1);
+
+    for dy in -border..=border {
+        for dx in -border..=border {
+            let distance = dx * dx + dy * dy;
+            if distance > border * border {
+                continue;
+            }
+
+            let pixel = if distance <= radius * radius {
+                color
+            } else {
+                BORDER_COLOR
+            };
+            blend_pixel(img, x + dx, y + dy, pixel);
+        }
+    }
+}
+
+// 文字列を描画したときの大きさ
+pub fn text_size(text: &str, scale: u32) -> (u32, u32) {
+    let count = text.chars().count() as u32;
+    ((count * 6).saturating_sub(1) * scale, 7 * scale)
+}
+
+// 左上を(x, y)として文字列を描画します(フォントにない文字は空白になります)
+pub fn draw_text(img: &mut RgbaImage, text: &str, x: i32, y: i32, scale: u32, color: Rgba<u8>) {
+    let scale = scale as i32;
+
+    for (index, c) in text.chars().enumerate() {
+        let c = c.to_ascii_uppercase();
+        let glyph = match FONT.iter().find(|(f, _)| *f == c) {
+            Some((_, glyph)) => glyph,
+            None => continue,
+        };
+
+        let left = x + index as i32 * 6 * scale;
+        for (row, bits) in glyph.iter().enumerate() {
+            for col in 0..5 {
+                if bits & (0x10 >> col) == 0 {
+                    continue;
+                }
+
+                for dy in 0..scale {
+                    for dx in 0..scale {
+                        blend_pixel(img, left + col * scale + dx, y + row as i32 * scale + dy, color);
+                    }
+                }
+            }
+        }
+    }
+}
+
+// 半透明の背景付きで文字列を描画します(y は上端、x は中央)
+pub fn draw_label(img: &mut RgbaImage, text: &str, center_x: i32, y: i32, scale: u32) {
+    let (width, height) = text_size(text, scale);
+    let padding = 2 * scale as i32;
+    let left = center_x - width as i32 / 2;
+
+    for py in (y - padding)..(y + height as i32 + padding) {
+        for px in (left - padding)..(left + width as i32 + padding) {
+            blend_pixel(img, px, py, LABEL_BACKGROUND);
+        }
+    }
+
+    draw_text(img, text, left, y, scale, TEXT_COLOR);
+}
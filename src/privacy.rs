@@ -0,0 +1,267 @@
+// 自宅や職場の周辺を隠すプライバシーゾーン
+// ゾーン内の出発・到着部分を切り捨て、ゾーン内ではアイコンを隠して地図を止め、地図をぼかすか色を抜きます
+use crate::{
+    track_point::{self, TrackLog, TrackPoint, Waypoint},
+    trim::{self, Area, TimeRange},
+};
+use anyhow::Result;
+use chrono::{TimeZone, Utc};
+use image::{imageops, RgbaImage};
+use std::{f64::consts::PI, str::FromStr};
+
+// 画像上の外接矩形(左, 上, 右, 下)
+type Bounds = (f64, f64, f64, f64);
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PrivacyZone {
+    // 中心(緯度, 経度)と半径(m)
+    Circle { lat: f64, lng: f64, radius: f64 },
+    Area(Area),
+}
+
+impl PrivacyZone {
+    // "緯度,経度,半径(m)"
+    pub fn parse_circle(value: &str) -> Result<Self> {
+        let values: Vec<Option<f64>> = value.split(',').map(|x| x.trim().parse().ok()).collect();
+        match values.as_slice() {
+            [Some(lat), Some(lng), Some(radius)] if *radius > 0.0 => Ok(PrivacyZone::Circle {
+                lat: *lat,
+                lng: *lng,
+                radius: *radius,
+            }),
+            _ => Err(anyhow::anyhow!("プライバシーゾーン \"{}\" は 緯度,経度,半径(m) で指定してください", value)),
+        }
+    }
+
+    pub fn contains(&self, point: &TrackPoint) -> bool {
+        match self {
+            PrivacyZone::Circle { lat, lng, radius } => {
+                track_point::distance(point, &TrackPoint::new(point.time, *lat, *lng)) <= *radius
+            }
+            PrivacyZone::Area(area) => area.contains(point),
+        }
+    }
+}
+
+// ゾーン内の地図の隠し方
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PrivacyStyle {
+    Blur,
+    Desaturate,
+}
+
+impl FromStr for PrivacyStyle {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "blur" => Ok(PrivacyStyle::Blur),
+            "desaturate" => Ok(PrivacyStyle::Desaturate),
+            _ => Err(anyhow::anyhow!("プライバシーゾーンの隠し方は blur, desaturate のいずれかです")),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Privacy {
+    pub zones: Vec<PrivacyZone>,
+    pub style: PrivacyStyle,
+}
+
+impl Privacy {
+    pub fn contains(&self, point: &TrackPoint) -> bool {
+        self.zones.iter().any(|zone| zone.contains(point))
+    }
+
+    pub fn contains_waypoint(&self, waypoint: &Waypoint) -> bool {
+        self.contains(&TrackPoint::new(Utc.timestamp(0, 0), waypoint.lat, waypoint.lng))
+    }
+
+    // ゾーン内のポイントとウェイポイントを取り除きます(ゾーンを通り抜けた所でセグメントを分けます)
+    pub fn remove_from(&self, track: &mut TrackLog) {
+        if self.zones.is_empty() {
+            return;
+        }
+
+        let mut segments: Vec<Vec<TrackPoint>> = Vec::new();
+        for segment in &track.segments {
+            let mut current: Vec<TrackPoint> = Vec::new();
+            for point in segment {
+                if !self.contains(point) {
+                    current.push(*point);
+                } else if !current.is_empty() {
+                    segments.push(std::mem::take(&mut current));
+                }
+            }
+            if !current.is_empty() {
+                segments.push(current);
+            }
+        }
+        track.segments = segments;
+        track.waypoints.retain(|waypoint| !self.contains_waypoint(waypoint));
+    }
+
+    // 最初にゾーンの外に出てから、最後にゾーンに入るまで
+    pub fn time_range(&self, track: &TrackLog) -> Result<TimeRange> {
+        if self.zones.is_empty() {
+            return Ok((None, None));
+        }
+
+        let mut outside = track.segments.iter().flatten().filter(|point| !self.contains(point));
+        let first = outside
+            .next()
+            .ok_or_else(|| anyhow::anyhow!("トラック全体がプライバシーゾーンの中にあります"))?;
+        let last = outside.next_back().unwrap_or(first);
+
+        Ok((Some(first.time), Some(last.time)))
+    }
+
+    // 地図画像のゾーン内を隠します
+    // to_pixel は緯度・経度から画像上の位置、meters_per_pixel は1ピクセルあたりの距離(m)です
+    pub fn apply(&self, img: &mut RgbaImage, to_pixel: impl Fn(f64, f64) -> (f64, f64), meters_per_pixel: f64) {
+        let (width, height) = img.dimensions();
+        let mut mask = vec![false; (width * height) as usize];
+        let mut masked = false;
+
+        for zone in &self.zones {
+            // 画像上の外接矩形と、内側かどうかの判定
+            let (bounds, inside): (Bounds, Box<dyn Fn(f64, f64) -> bool>) = match zone {
+                PrivacyZone::Circle { lat, lng, radius } => {
+                    // 隠す円の中心が指定した位置にならないように、半径を1.5倍にしてずらします
+                    let (cx, cy) = to_pixel(*lat, *lng);
+                    let r = radius / meters_per_pixel;
+                    let (dx, dy) = mask_direction(*lat, *lng);
+                    let (cx, cy, r) = (cx + dx * r * 0.5, cy + dy * r * 0.5, r * 1.5);
+                    (
+                        (cx - r, cy - r, cx + r, cy + r),
+                        Box::new(move |x, y| (x - cx).powi(2) + (y - cy).powi(2) <= r * r),
+                    )
+                }
+                PrivacyZone::Area(area) => {
+                    let polygons: Vec<Vec<trim::Ring>> = area
+                        .polygons()
+                        .iter()
+                        .map(|rings| {
+                            rings
+                                .iter()
+                                .map(|ring| ring.iter().map(|(lng, lat)| to_pixel(*lat, *lng)).collect())
+                                .collect()
+                        })
+                        .collect();
+                    let outers = polygons.iter().flat_map(|rings| rings[0].iter());
+                    let bounds = outers.fold((f64::MAX, f64::MAX, f64::MIN, f64::MIN), |b, (x, y)| {
+                        (b.0.min(*x), b.1.min(*y), b.2.max(*x), b.3.max(*y))
+                    });
+                    (
+                        bounds,
+                        Box::new(move |x, y| polygons.iter().any(|rings| trim::polygon_contains(rings, x, y))),
+                    )
+                }
+            };
+
+            let (min_x, min_y) = (bounds.0.floor().max(0.0) as u32, bounds.1.floor().max(0.0) as u32);
+            let (max_x, max_y) = (bounds.2.ceil().min(width as f64) as u32, bounds.3.ceil().min(height as f64) as u32);
+            for y in min_y..max_y {
+                for x in min_x..max_x {
+                    if inside(x as f64 + 0.5, y as f64 + 0.5) {
+                        mask[(y * width + x) as usize] = true;
+                        masked = true;
+                    }
+                }
+            }
+        }
+
+        if !masked {
+            return;
+        }
+
+        match self.style {
+            PrivacyStyle::Blur => {
+                let blurred = imageops::blur(img, (width as f32 / 40.0).max(3.0));
+                for (x, y, pixel) in img.enumerate_pixels_mut() {
+                    if mask[(y * width + x) as usize] {
+                        *pixel = *blurred.get_pixel(x, y);
+                    }
+                }
+            }
+            PrivacyStyle::Desaturate => {
+                // 白っぽいグレーにして、道の形も目立たなくします
+                for (x, y, pixel) in img.enumerate_pixels_mut() {
+                    if mask[(y * width + x) as usize] {
+                        let luma = 0.299 * pixel[0] as f64 + 0.587 * pixel[1] as f64 + 0.114 * pixel[2] as f64;
+                        let value = (luma * 0.4 + 255.0 * 0.6) as u8;
+                        pixel[0] = value;
+                        pixel[1] = value;
+                        pixel[2] = value;
+                    }
+                }
+            }
+        }
+    }
+}
+
+// 隠す円をずらす向き(位置から決まるので、フレームごとには変わりません)
+fn mask_direction(lat: f64, lng: f64) -> (f64, f64) {
+    let angle = ((lat * 12.9898 + lng * 78.233).sin() * 43758.5453).fract() * 2.0 * PI;
+    (angle.cos(), angle.sin())
+}
+
+#[test]
+fn privacy_zone() {
+    use crate::track_point::{test_point, test_time, test_track};
+    use image::Rgba;
+
+    // 自宅(半径200m)を出て1km北へ走り、また自宅に戻る
+    let step = 10.0 / 111_195.0;
+    let mut points: Vec<TrackPoint> = (0..=100).map(|i| test_point(i, 35.0 + i as f64 * step)).collect();
+    points.extend((1..=100).map(|i| test_point(100 + i, 35.0 + (100 - i) as f64 * step)));
+    let track = test_track(points);
+
+    let privacy = Privacy {
+        zones: vec![PrivacyZone::parse_circle("35.0,139.5,200").unwrap()],
+        style: PrivacyStyle::Desaturate,
+    };
+    assert_eq!(privacy.time_range(&track).unwrap(), (Some(test_time(21)), Some(test_time(179))));
+    assert!(PrivacyZone::parse_circle("35.0,139.5").is_err());
+
+    // 1ピクセル10mで、画像の中心が自宅
+    let mut img = RgbaImage::from_pixel(100, 100, Rgba([200, 0, 0, 255]));
+    let to_pixel = |lat: f64, lng: f64| (50.0 + (lng - 139.5) * 91_000.0 / 10.0, 50.0 - (lat - 35.0) / step);
+    privacy.apply(&mut img, to_pixel, 10.0);
+    let center = img.get_pixel(50, 50);
+    assert!(center[0] == center[1] && center[1] == center[2]);
+    assert_eq!(*img.get_pixel(50, 99), Rgba([200, 0, 0, 255]));
+
+    // 隠した範囲の中心は自宅からずれている
+    let masked: Vec<(f64, f64)> = img
+        .enumerate_pixels()
+        .filter(|(_, _, pixel)| pixel[1] != 0)
+        .map(|(x, y, _)| (x as f64 + 0.5, y as f64 + 0.5))
+        .collect();
+    let count = masked.len() as f64;
+    let (mx, my) = masked.iter().fold((0.0, 0.0), |(sx, sy), (x, y)| (sx + x / count, sy + y / count));
+    assert!((mx - 50.0).hypot(my - 50.0) > 5.0, "{} {}", mx, my);
+    assert!(privacy.contains_waypoint(&Waypoint {
+        name: "HOME".to_string(),
+        lat: 35.0,
+        lng: 139.5
+    }));
+
+    // 全体がゾーンの中
+    let privacy = Privacy {
+        zones: vec![PrivacyZone::parse_circle("35.0,139.5,2000").unwrap()],
+        style: PrivacyStyle::Blur,
+    };
+    assert!(privacy.time_range(&track).is_err());
+
+    // 途中でゾーン(500m地点の半径100m)を通り抜ける場合は、ゾーン内を除いて前後のセグメントに分ける
+    let privacy = Privacy {
+        zones: vec![PrivacyZone::parse_circle(&format!("{},139.5,100", 35.0 + 50.0 * step)).unwrap()],
+        style: PrivacyStyle::Blur,
+    };
+    let mut exported = test_track(track.segments[0][..=100].to_vec());
+    privacy.remove_from(&mut exported);
+    assert_eq!(exported.segments.len(), 2);
+    assert!(exported.segments.iter().flatten().all(|point| !privacy.contains(point)));
+    assert_eq!((exported.segments[0].last().unwrap().time, exported.segments[1][0].time), (test_time(39), test_time(61)));
+}
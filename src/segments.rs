@@ -0,0 +1,524 @@
+// ユーザーが定義したセグメント(開始・終了のゲート、または経路と許容距離)の走行の検出とタイム、記録との比較
+use crate::{
+    laps,
+    overlay::Notifier,
+    track_point::{TrackLog, TrackPoint},
+};
+use anyhow::Result;
+use chrono::{DateTime, Duration, Utc};
+use serde_json::{json, Value};
+use std::{fmt, fs, path::Path};
+
+// 経路の許容距離(m)の省略時の値
+const DEFAULT_TOLERANCE: f64 = 20.0;
+// 緯度1度あたりの距離(m)
+const METERS_PER_DEGREE: f64 = 111_195.0;
+
+// 緯度, 経度の2点を結ぶ線
+pub type Gate = ((f64, f64), (f64, f64));
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SegmentShape {
+    // 開始のゲートを通過してから終了のゲートを通過するまで
+    Gates { start: Gate, end: Gate },
+    // 経路(緯度, 経度の列)の始点から終点まで、経路から許容距離(m)以内を走ったとき
+    Line { points: Vec<(f64, f64)>, tolerance: f64 },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Segment {
+    pub name: String,
+    pub shape: SegmentShape,
+}
+
+impl Segment {
+    pub fn load(path: &str) -> Result<Vec<Self>> {
+        Self::from_geojson(&fs::read_to_string(path)?)
+    }
+
+    // GeoJSON の Feature ごとに1つのセグメント(座標は GeoJSON と同じ経度, 緯度の順)
+    // LineString は経路(properties.tolerance が許容距離)、properties の start と end は [[経度, 緯度], [経度, 緯度]] のゲート
+    pub fn from_geojson(text: &str) -> Result<Vec<Self>> {
+        let value: Value = serde_json::from_str(text)?;
+        let features = match value["type"].as_str() {
+            Some("FeatureCollection") => value["features"].as_array().cloned().unwrap_or_default(),
+            Some("Feature") => vec![value],
+            _ => Vec::new(),
+        };
+
+        let segments = features
+            .iter()
+            .enumerate()
+            .map(|(index, feature)| {
+                let properties = &feature["properties"];
+                let name = properties["name"]
+                    .as_str()
+                    .map(|x| x.to_string())
+                    .unwrap_or_else(|| format!("セグメント{}", index + 1));
+                let error = || anyhow::anyhow!("セグメント \"{}\" の座標が正しくありません", name);
+
+                let shape = if feature["geometry"]["type"].as_str() == Some("LineString") {
+                    let points = read_positions(&feature["geometry"]["coordinates"]).ok_or_else(error)?;
+                    if points.len() < 2 {
+                        return Err(error());
+                    }
+                    SegmentShape::Line {
+                        points,
+                        tolerance: properties["tolerance"].as_f64().unwrap_or(DEFAULT_TOLERANCE),
+                    }
+                } else {
+                    let gate = |key: &str| match read_positions(&properties[key])?.as_slice() {
+                        [from, to] => Some((*from, *to)),
+                        _ => None,
+                    };
+                    SegmentShape::Gates {
+                        start: gate("start").ok_or_else(error)?,
+                        end: gate("end").ok_or_else(error)?,
+                    }
+                };
+
+                Ok(Segment { name, shape })
+            })
+            .collect::<Result<Vec<Segment>>>()?;
+
+        if segments.is_empty() {
+            return Err(anyhow::anyhow!("セグメントが定義されていません"));
+        }
+        Ok(segments)
+    }
+
+    // トラックの中のこのセグメントの走行
+    pub fn efforts(&self, track: &TrackLog) -> Vec<Effort> {
+        let points = laps::cumulative_distances(track);
+        if points.is_empty() {
+            return Vec::new();
+        }
+
+        let times = match &self.shape {
+            SegmentShape::Gates { start, end } => {
+                let starts = laps::line_crossings(track, start.0, start.1);
+                let ends = laps::line_crossings(track, end.0, end.1);
+
+                // 開始のゲートの後で最初に終了のゲートを通過するまで(重なる走行は数えません)
+                let mut times: Vec<(DateTime<Utc>, DateTime<Utc>)> = Vec::new();
+                for start in starts {
+                    if times.last().is_some_and(|(_, end)| start < *end) {
+                        continue;
+                    }
+                    if let Some(end) = ends.iter().find(|end| **end > start) {
+                        times.push((start, *end));
+                    }
+                }
+                times
+            }
+            SegmentShape::Line { points: line, tolerance } => line_efforts(&points, line, *tolerance),
+        };
+
+        times
+            .into_iter()
+            .map(|(start, end)| Effort {
+                segment: self.name.clone(),
+                start,
+                end,
+                distance: laps::distance_at_time(&points, end) - laps::distance_at_time(&points, start),
+            })
+            .collect()
+    }
+}
+
+fn read_positions(value: &Value) -> Option<Vec<(f64, f64)>> {
+    value
+        .as_array()?
+        .iter()
+        .map(|position| Some((position.get(1)?.as_f64()?, position.get(0)?.as_f64()?)))
+        .collect()
+}
+
+// 区間 a→b の上で target に最も近い位置(0.0〜1.0)と距離(m)
+fn nearest_on_line(a: (f64, f64), b: (f64, f64), target: (f64, f64)) -> (f64, f64) {
+    // target を原点に、経度を緯度に合わせて縮めた平面で計算します
+    let scale = target.0.to_radians().cos();
+    let xy = |(lat, lng): (f64, f64)| ((lng - target.1) * scale * METERS_PER_DEGREE, (lat - target.0) * METERS_PER_DEGREE);
+    let (a, b) = (xy(a), xy(b));
+    let (dx, dy) = (b.0 - a.0, b.1 - a.1);
+
+    let length = dx * dx + dy * dy;
+    let t = if length > 0.0 {
+        (-(a.0 * dx + a.1 * dy) / length).clamp(0.0, 1.0)
+    } else {
+        0.0
+    };
+    let (x, y) = (a.0 + dx * t, a.1 + dy * t);
+    (t, (x * x + y * y).sqrt())
+}
+
+// 経路の始点の近くから、経路の頂点を順に通り、経路を外れずに終点の近くまで走った区間の開始・終了時刻
+fn line_efforts(points: &[(&TrackPoint, f64)], line: &[(f64, f64)], tolerance: f64) -> Vec<(DateTime<Utc>, DateTime<Utc>)> {
+    let position = |index: usize| (points[index].0.lat, points[index].0.lng);
+    // ポイント index から次のポイントまでの区間で target に最も近い位置と距離
+    let near = |index: usize, target: (f64, f64)| nearest_on_line(position(index), position(index + 1), target);
+    let time_at = |index: usize, t: f64| {
+        let (prev, next) = (points[index].0.time, points[index + 1].0.time);
+        prev + Duration::milliseconds(((next - prev).num_milliseconds() as f64 * t).round() as i64)
+    };
+    // 許容距離内で target に最も近づく区間(近づき続ける間だけ進めます)
+    let closest = |mut index: usize, target: (f64, f64)| {
+        while index + 2 < points.len() && near(index + 1, target).1 < near(index, target).1 {
+            index += 1;
+        }
+        index
+    };
+    let off_route = |index: usize| {
+        line.windows(2)
+            .all(|pair| nearest_on_line(pair[0], pair[1], position(index)).1 > tolerance)
+    };
+
+    let (first, last) = (line[0], line[line.len() - 1]);
+    let mut efforts = Vec::new();
+    let mut index = 0;
+    while index + 1 < points.len() {
+        if near(index, first).1 > tolerance {
+            index += 1;
+            continue;
+        }
+        let start_index = closest(index, first);
+        let start = time_at(start_index, near(start_index, first).0);
+
+        // 頂点を順に通ったか確かめながら、終点まで進めます
+        let mut vertex = 1;
+        let mut end = None;
+        for current in start_index..points.len() - 1 {
+            while vertex < line.len() - 1 && near(current, line[vertex]).1 <= tolerance {
+                vertex += 1;
+            }
+            if vertex == line.len() - 1 && near(current, last).1 <= tolerance {
+                let end_index = closest(current, last);
+                end = Some((end_index, time_at(end_index, near(end_index, last).0)));
+                break;
+            }
+            if off_route(current + 1) {
+                break;
+            }
+        }
+
+        match end {
+            Some((end_index, end)) => {
+                efforts.push((start, end));
+                index = end_index + 1;
+            }
+            None => index = start_index + 1,
+        }
+    }
+
+    efforts
+}
+
+// セグメントの1回の走行
+#[derive(Debug, Clone, PartialEq)]
+pub struct Effort {
+    pub segment: String,
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    // 距離(m)
+    pub distance: f64,
+}
+
+impl Effort {
+    pub fn duration(&self) -> Duration {
+        self.end - self.start
+    }
+
+    // 平均速度(km/h)
+    pub fn avg_speed(&self) -> f64 {
+        let seconds = self.duration().num_milliseconds() as f64 / 1000.0;
+        if seconds > 0.0 {
+            self.distance / seconds * 3.6
+        } else {
+            0.0
+        }
+    }
+}
+
+// 過去の走行の記録(JSONファイル)
+#[derive(Debug, Clone, Default)]
+pub struct History {
+    pub efforts: Vec<Effort>,
+}
+
+impl History {
+    // ファイルがなければ空の記録にします
+    pub fn load(path: &str) -> Result<Self> {
+        if !Path::new(path).exists() {
+            return Ok(Self::default());
+        }
+
+        let value: Value = serde_json::from_str(&fs::read_to_string(path)?)?;
+        let error = || anyhow::anyhow!("セグメントの記録 \"{}\" の形式が正しくありません", path);
+        let efforts = value["efforts"]
+            .as_array()
+            .ok_or_else(error)?
+            .iter()
+            .map(|effort| {
+                let time = |key: &str| {
+                    DateTime::parse_from_rfc3339(effort[key].as_str()?)
+                        .ok()
+                        .map(|x| x.with_timezone(&Utc))
+                };
+                Some(Effort {
+                    segment: effort["segment"].as_str()?.to_string(),
+                    start: time("start")?,
+                    end: time("end")?,
+                    distance: effort["distance_m"].as_f64()?,
+                })
+            })
+            .collect::<Option<Vec<Effort>>>()
+            .ok_or_else(error)?;
+
+        Ok(Self { efforts })
+    }
+
+    pub fn save(&self, path: &str) -> Result<()> {
+        let efforts: Vec<Value> = self
+            .efforts
+            .iter()
+            .map(|effort| {
+                json!({
+                    "segment": effort.segment,
+                    "start": effort.start.to_rfc3339(),
+                    "end": effort.end.to_rfc3339(),
+                    "distance_m": effort.distance,
+                })
+            })
+            .collect();
+        fs::write(path, serde_json::to_string_pretty(&json!({ "efforts": efforts }))? + "\n")?;
+        Ok(())
+    }
+
+    // 同じセグメント・開始時刻の記録は置き換えます(同じファイルを何度処理しても増えません)
+    pub fn record(&mut self, efforts: &[Effort]) {
+        for effort in efforts {
+            self.efforts
+                .retain(|x| !(x.segment == effort.segment && x.start == effort.start));
+            self.efforts.push(effort.clone());
+        }
+        self.efforts.sort_by(|a, b| (&a.segment, a.start).cmp(&(&b.segment, b.start)));
+    }
+
+    // 同じセグメントのこの走行より前の記録の中での順位と、その中のベスト
+    pub fn rank(&self, effort: &Effort) -> RankedEffort {
+        let others: Vec<Duration> = self
+            .efforts
+            .iter()
+            .filter(|x| x.segment == effort.segment && x.start < effort.start)
+            .map(|x| x.duration())
+            .collect();
+
+        RankedEffort {
+            effort: effort.clone(),
+            rank: others.iter().filter(|x| **x < effort.duration()).count() + 1,
+            count: others.len() + 1,
+            best: others.iter().min().copied(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RankedEffort {
+    pub effort: Effort,
+    // この走行より前の記録の中での順位(1から)と記録の数(この走行を含みます)
+    pub rank: usize,
+    pub count: usize,
+    // この走行より前の(この走行を除いた)ベストのタイム
+    pub best: Option<Duration>,
+}
+
+impl RankedEffort {
+    pub fn is_personal_best(&self) -> bool {
+        self.rank == 1
+    }
+
+    // 走り終えたときに表示する "SEGMENT 12:34 PB" や "SEGMENT 12:34 +0:45 3/10"
+    pub fn label(&self) -> String {
+        let time = laps::format_duration(self.effort.duration());
+        match self.best {
+            Some(best) if !self.is_personal_best() => format!(
+                "{} {} {} {}/{}",
+                label_name(&self.effort.segment),
+                time,
+                format_delta(self.effort.duration() - best),
+                self.rank,
+                self.count
+            ),
+            _ => format!("{} {} PB", label_name(&self.effort.segment), time),
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "segment": self.effort.segment,
+            "start": self.effort.start.to_rfc3339(),
+            "end": self.effort.end.to_rfc3339(),
+            "distance_m": self.effort.distance,
+            "duration_s": self.effort.duration().num_milliseconds() as f64 / 1000.0,
+            "avg_speed_kmh": self.effort.avg_speed(),
+            "rank": self.rank,
+            "count": self.count,
+            "best_s": self.best.map(|x| x.num_milliseconds() as f64 / 1000.0),
+        })
+    }
+}
+
+impl fmt::Display for RankedEffort {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "セグメント {}: {} {:.1} km/h {}位 / {}回",
+            self.effort.segment,
+            laps::format_duration(self.effort.duration()),
+            self.effort.avg_speed(),
+            self.rank,
+            self.count
+        )?;
+        match self.best {
+            Some(best) => write!(
+                f,
+                " ({}ベスト {} との差 {})",
+                if self.is_personal_best() { "自己ベスト更新、前回までの" } else { "自己" },
+                laps::format_duration(best),
+                format_delta(self.effort.duration() - best)
+            ),
+            None => write!(f, " (初回)"),
+        }
+    }
+}
+
+// フォントにない文字を含む名前は "SEGMENT" と表示します
+fn label_name(name: &str) -> String {
+    if name.is_ascii() {
+        name.to_uppercase()
+    } else {
+        "SEGMENT".to_string()
+    }
+}
+
+fn format_delta(delta: Duration) -> String {
+    if delta < Duration::zero() {
+        format!("-{}", laps::format_duration(-delta))
+    } else {
+        format!("+{}", laps::format_duration(delta))
+    }
+}
+
+// 動画でセグメントの走行中にタイムとベストとの差、走り終えたときに結果を表示します
+pub struct SegmentTimer<'a> {
+    efforts: Vec<RankedEffort>,
+    points: Vec<(&'a TrackPoint, f64)>,
+    notifier: Notifier<usize>,
+}
+
+impl<'a> SegmentTimer<'a> {
+    // frames は走り終えたときに結果を表示するフレーム数
+    pub fn new(efforts: Vec<RankedEffort>, track: &'a TrackLog, frames: usize) -> Self {
+        let ends = efforts.iter().enumerate().map(|(index, x)| (x.effort.end, index)).collect();
+        Self {
+            efforts,
+            points: laps::cumulative_distances(track),
+            notifier: Notifier::new(ends, frames),
+        }
+    }
+
+    // フレームの時刻に表示する文字列(フレームの順に呼び出します)
+    pub fn update(&mut self, time: DateTime<Utc>) -> Option<String> {
+        let finished = self.notifier.update(time);
+
+        if let Some(current) = self
+            .efforts
+            .iter()
+            .find(|x| x.effort.start <= time && time < x.effort.end)
+        {
+            let effort = &current.effort;
+            let elapsed = time - effort.start;
+            let mut text = format!("{} {}", label_name(&effort.segment), laps::format_duration(elapsed));
+
+            // ベストと同じペースで走った場合の、今の距離での経過時間との差
+            if let Some(best) = current.best {
+                if effort.distance > 0.0 {
+                    let done = laps::distance_at_time(&self.points, time) - laps::distance_at_time(&self.points, effort.start);
+                    let expected = best.num_milliseconds() as f64 * done / effort.distance;
+                    text += &format!(" {}", format_delta(elapsed - Duration::milliseconds(expected.round() as i64)));
+                }
+            }
+            return Some(text);
+        }
+
+        finished.map(|index| self.efforts[index].label())
+    }
+}
+
+#[test]
+fn segment_efforts() {
+    use crate::track_point::{test_point, test_time, test_track};
+
+    // 1秒ごとに約10m北へ2km進む(時速36km)
+    let step = 10.0 / METERS_PER_DEGREE;
+    let points: Vec<TrackPoint> = (0..=200).map(|i| test_point(i, 35.0 + i as f64 * step)).collect();
+    let track = test_track(points);
+
+    // 500m地点と1500m地点のゲート、200m地点から1800m地点までの経路、東に外れる経路
+    let geojson = format!(
+        r#"{{"type": "FeatureCollection", "features": [
+            {{"type": "Feature", "geometry": null, "properties": {{"name": "gates",
+                "start": [[139.49, {a}], [139.51, {a}]], "end": [[139.49, {b}], [139.51, {b}]]}}}},
+            {{"type": "Feature", "properties": {{"name": "line", "tolerance": 15}},
+                "geometry": {{"type": "LineString", "coordinates": [[139.5, {c}], [139.5001, {d}], [139.5, {e}]]}}}},
+            {{"type": "Feature", "properties": {{"name": "east"}},
+                "geometry": {{"type": "LineString", "coordinates": [[139.5, {c}], [139.52, {c}]]}}}}]}}"#,
+        a = 35.0 + 50.5 * step,
+        b = 35.0 + 150.5 * step,
+        c = 35.0 + 20.0 * step,
+        d = 35.0 + 100.0 * step,
+        e = 35.0 + 180.0 * step
+    );
+    let segments = Segment::from_geojson(&geojson).unwrap();
+    assert_eq!(segments.len(), 3);
+
+    let efforts = segments[0].efforts(&track);
+    assert_eq!(efforts.len(), 1);
+    assert_eq!(efforts[0].duration(), Duration::seconds(100));
+    assert!((efforts[0].distance - 1000.0).abs() < 1.0);
+
+    let efforts = segments[1].efforts(&track);
+    assert_eq!(efforts.len(), 1);
+    assert_eq!((efforts[0].start, efforts[0].end), (test_time(20), test_time(180)));
+    assert!(segments[2].efforts(&track).is_empty());
+
+    // 以前の記録(200秒)と比べます
+    let mut history = History::default();
+    let previous = Effort {
+        start: test_time(-86_400),
+        end: test_time(-86_400 + 200),
+        ..efforts[0].clone()
+    };
+    history.record(&[previous]);
+    history.record(&efforts);
+    history.record(&efforts);
+    assert_eq!(history.efforts.len(), 2);
+    let ranked = history.rank(&efforts[0]);
+    assert_eq!((ranked.rank, ranked.count, ranked.best), (1, 2, Some(Duration::seconds(200))));
+    assert_eq!(ranked.label(), "LINE 2:40 PB");
+
+    // この走行より後の記録は順位とベストに含めない
+    history.record(&[Effort {
+        start: test_time(86_400),
+        end: test_time(86_400 + 100),
+        ..efforts[0].clone()
+    }]);
+    assert_eq!(history.rank(&efforts[0]), ranked);
+
+    // 80秒で800m進んだ時点。ベストのペースでは100秒かかるので20秒早い
+    let mut timer = SegmentTimer::new(vec![ranked], &track, 2);
+    assert_eq!(timer.update(test_time(19)), None);
+    assert_eq!(timer.update(test_time(100)).as_deref(), Some("LINE 1:20 -0:20"));
+    assert_eq!(timer.update(test_time(180)).as_deref(), Some("LINE 2:40 PB"));
+    assert!(timer.update(test_time(181)).is_some() && timer.update(test_time(182)).is_none());
+}
@@ -0,0 +1,222 @@
+// GPSの揺れを抑える平滑化(カルマンフィルタ、スプライン補間)
+use crate::track_point::{TrackLog, TrackPoint};
+use std::str::FromStr;
+
+// 加速度の揺らぎ(m/s^2)。カルマンフィルタのプロセスノイズに使います
+const ACCELERATION_NOISE: f64 = 1.0;
+// 1度あたりの距離(m)
+const METERS_PER_DEGREE: f64 = 111_320.0;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Smoothing {
+    None,
+    // 等速モデルのカルマンフィルタ(前後から平滑化)で位置を補正します
+    Kalman,
+    // ポイントの間を Catmull-Rom スプライン(centripetal)で補間します
+    Spline,
+}
+
+impl FromStr for Smoothing {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Smoothing::None),
+            "kalman" => Ok(Smoothing::Kalman),
+            "spline" => Ok(Smoothing::Spline),
+            _ => Err(anyhow::anyhow!("平滑化の方法は none, kalman, spline のいずれかです")),
+        }
+    }
+}
+
+type Matrix = [[f64; 2]; 2];
+
+fn mul(a: &Matrix, b: &Matrix) -> Matrix {
+    let mut result = [[0.0; 2]; 2];
+    for (i, row) in result.iter_mut().enumerate() {
+        for (j, value) in row.iter_mut().enumerate() {
+            *value = a[i][0] * b[0][j] + a[i][1] * b[1][j];
+        }
+    }
+    result
+}
+
+fn transpose(a: &Matrix) -> Matrix {
+    [[a[0][0], a[1][0]], [a[0][1], a[1][1]]]
+}
+
+fn inverse(a: &Matrix) -> Matrix {
+    let det = a[0][0] * a[1][1] - a[0][1] * a[1][0];
+    [[a[1][1] / det, -a[0][1] / det], [-a[1][0] / det, a[0][0] / det]]
+}
+
+// セグメントごとに、位置をカルマンフィルタで補正します(noise はGPSの誤差(m))
+pub fn kalman_track(track: &mut TrackLog, noise: f64) {
+    for segment in &mut track.segments {
+        if segment.len() < 3 {
+            continue;
+        }
+
+        // 最初のポイントからの距離(m)に直して、南北・東西を別々に処理します
+        let origin = segment[0];
+        let scale_x = METERS_PER_DEGREE * origin.lat.to_radians().cos();
+        let times: Vec<f64> = segment
+            .iter()
+            .map(|p| (p.time - origin.time).num_milliseconds() as f64 / 1000.0)
+            .collect();
+
+        let ys: Vec<f64> = segment.iter().map(|p| (p.lat - origin.lat) * METERS_PER_DEGREE).collect();
+        let xs: Vec<f64> = segment.iter().map(|p| (p.lng - origin.lng) * scale_x).collect();
+        let ys = kalman_smooth(&times, &ys, noise);
+        let xs = kalman_smooth(&times, &xs, noise);
+
+        for (point, (x, y)) in segment.iter_mut().zip(xs.iter().zip(&ys)) {
+            point.lat = origin.lat + y / METERS_PER_DEGREE;
+            point.lng = origin.lng + x / scale_x;
+        }
+    }
+}
+
+// 一次元の等速モデル(位置・速度)のカルマンフィルタと RTS スムーザ
+fn kalman_smooth(times: &[f64], values: &[f64], noise: f64) -> Vec<f64> {
+    let r = noise * noise;
+    let q = ACCELERATION_NOISE * ACCELERATION_NOISE;
+
+    // 前向きのフィルタ(予測値と補正値を保存します)
+    let mut filtered: Vec<([f64; 2], Matrix)> = Vec::with_capacity(values.len());
+    let mut predicted: Vec<([f64; 2], Matrix)> = Vec::with_capacity(values.len());
+    let mut transitions: Vec<Matrix> = Vec::with_capacity(values.len());
+
+    let mut x = [values[0], 0.0];
+    let mut p: Matrix = [[r, 0.0], [0.0, 100.0]];
+    for (index, z) in values.iter().enumerate() {
+        let dt = if index == 0 { 0.0 } else { times[index] - times[index - 1] };
+
+        // 予測
+        let f: Matrix = [[1.0, dt], [0.0, 1.0]];
+        let x_pred = [x[0] + x[1] * dt, x[1]];
+        let mut p_pred = mul(&mul(&f, &p), &transpose(&f));
+        p_pred[0][0] += q * dt.powi(4) / 4.0;
+        p_pred[0][1] += q * dt.powi(3) / 2.0;
+        p_pred[1][0] += q * dt.powi(3) / 2.0;
+        p_pred[1][1] += q * dt.powi(2);
+
+        // 観測(位置のみ)で補正
+        let s = p_pred[0][0] + r;
+        let k = [p_pred[0][0] / s, p_pred[1][0] / s];
+        let y = z - x_pred[0];
+        x = [x_pred[0] + k[0] * y, x_pred[1] + k[1] * y];
+        p = [
+            [(1.0 - k[0]) * p_pred[0][0], (1.0 - k[0]) * p_pred[0][1]],
+            [p_pred[1][0] - k[1] * p_pred[0][0], p_pred[1][1] - k[1] * p_pred[0][1]],
+        ];
+
+        transitions.push(f);
+        predicted.push((x_pred, p_pred));
+        filtered.push((x, p));
+    }
+
+    // 後ろ向きに平滑化します
+    let mut smoothed = filtered.clone();
+    for index in (0..values.len() - 1).rev() {
+        let (x, p) = filtered[index];
+        let (x_pred, p_pred) = predicted[index + 1];
+        let f = transitions[index + 1];
+
+        let c = mul(&mul(&p, &transpose(&f)), &inverse(&p_pred));
+        let (x_next, p_next) = smoothed[index + 1];
+        let diff = [x_next[0] - x_pred[0], x_next[1] - x_pred[1]];
+        let x = [
+            x[0] + c[0][0] * diff[0] + c[0][1] * diff[1],
+            x[1] + c[1][0] * diff[0] + c[1][1] * diff[1],
+        ];
+
+        let mut p_diff = p_next;
+        for (i, row) in p_diff.iter_mut().enumerate() {
+            for (j, value) in row.iter_mut().enumerate() {
+                *value -= p_pred[i][j];
+            }
+        }
+        let correction = mul(&mul(&c, &p_diff), &transpose(&c));
+        let mut p = p;
+        for (i, row) in p.iter_mut().enumerate() {
+            for (j, value) in row.iter_mut().enumerate() {
+                *value += correction[i][j];
+            }
+        }
+
+        smoothed[index] = (x, p);
+    }
+
+    smoothed.iter().map(|(x, _)| x[0]).collect()
+}
+
+// p1 と p2 の間の位置を centripetal Catmull-Rom スプラインで求めます(ratio は 0.0〜1.0)
+// p0, p3 がない場合(トラックの端やギャップ)は p1, p2 を延長した点を使います
+pub fn catmull_rom(
+    p0: Option<&TrackPoint>,
+    p1: &TrackPoint,
+    p2: &TrackPoint,
+    p3: Option<&TrackPoint>,
+    ratio: f64,
+) -> (f64, f64) {
+    let point = |p: &TrackPoint| (p.lat, p.lng);
+    let (b, c) = (point(p1), point(p2));
+    let a = p0.map_or((2.0 * b.0 - c.0, 2.0 * b.1 - c.1), point);
+    let d = p3.map_or((2.0 * c.0 - b.0, 2.0 * c.1 - b.1), point);
+
+    // 点の間隔の平方根でパラメータを決めます
+    let knot = |from: (f64, f64), to: (f64, f64)| {
+        let length = ((to.0 - from.0).powi(2) + (to.1 - from.1).powi(2)).sqrt().sqrt();
+        length.max(1e-12)
+    };
+    let t0 = 0.0;
+    let t1 = t0 + knot(a, b);
+    let t2 = t1 + knot(b, c);
+    let t3 = t2 + knot(c, d);
+    let t = t1 + (t2 - t1) * ratio;
+
+    let lerp = |p: (f64, f64), q: (f64, f64), from: f64, to: f64| {
+        let (wp, wq) = ((to - t) / (to - from), (t - from) / (to - from));
+        (p.0 * wp + q.0 * wq, p.1 * wp + q.1 * wq)
+    };
+    let a1 = lerp(a, b, t0, t1);
+    let a2 = lerp(b, c, t1, t2);
+    let a3 = lerp(c, d, t2, t3);
+    let b1 = lerp(a1, a2, t0, t2);
+    let b2 = lerp(a2, a3, t1, t3);
+    lerp(b1, b2, t1, t2)
+}
+
+#[test]
+fn smooth_jitter() {
+    use crate::track_point::{test_time, test_track};
+
+    // 東へ一定の速さで進むが、南北に±5mずつ揺れている
+    let jitter = 5.0 / METERS_PER_DEGREE;
+    let points: Vec<TrackPoint> = (0..100)
+        .map(|i| {
+            let lat = 35.0 + if i % 2 == 0 { jitter } else { -jitter };
+            TrackPoint::new(test_time(i), lat, 139.5 + i as f64 * 0.0001)
+        })
+        .collect();
+    let mut track = test_track(points.clone());
+
+    kalman_track(&mut track, 5.0);
+    let max_error = track.segments[0][5..95]
+        .iter()
+        .map(|p| (p.lat - 35.0).abs() * METERS_PER_DEGREE)
+        .fold(0.0, f64::max);
+    assert!(max_error < 1.0, "{}", max_error);
+    // 進み方は変わらない
+    assert!((track.segments[0][50].lng - points[50].lng).abs() * METERS_PER_DEGREE < 1.0);
+
+    // スプラインは両端を通り、等間隔の直線上では直線補間と同じ
+    let line: Vec<TrackPoint> = (0..4).map(|i| TrackPoint::new(points[i].time, 35.0, 139.5 + i as f64 * 0.001)).collect();
+    let (lat, lng) = catmull_rom(Some(&line[0]), &line[1], &line[2], Some(&line[3]), 0.0);
+    assert_eq!((lat, lng), (line[1].lat, line[1].lng));
+    let (_, lng) = catmull_rom(Some(&line[0]), &line[1], &line[2], Some(&line[3]), 0.5);
+    assert!((lng - 139.5015).abs() < 1e-9);
+    let (_, lng) = catmull_rom(None, &line[1], &line[2], None, 0.25);
+    assert!((lng - 139.50125).abs() < 1e-9);
+}
@@ -0,0 +1,215 @@
+// DJIのドローンが動画と一緒に出力する字幕ファイル(.SRT)の読み込み
+//
+// 1
+// 00:00:00,000 --> 00:00:00,033
+// <font size="28">FrameCnt: 1, DiffTime: 33ms
+// 2023-05-20 10:11:12.345
+// [iso: 100] ... [latitude: 35.744694] [longitude: 139.519397] [rel_alt: 1.200 abs_alt: 103.450] </font>
+//
+// 機種によっては GPS(経度,緯度,高度) や [longtitude : ...] の形式で出力されます
+
+use crate::{
+    arguments,
+    track_point::{TrackLog, TrackPoint},
+};
+use anyhow::Result;
+use chrono::{DateTime, Duration, NaiveDateTime, NaiveTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use std::io::BufRead;
+
+// 字幕一件分
+#[derive(Debug, Clone, Copy)]
+struct SrtCue {
+    start: Duration,
+    date_time: Option<NaiveDateTime>,
+    lat: f64,
+    lng: f64,
+    ele: Option<f64>,
+}
+
+// 字幕の日時は tz(なければこのコンピュータのタイムゾーン)の日時とします
+pub fn read<R: BufRead>(reader: R, tz: Option<Tz>) -> Result<TrackLog> {
+    let mut cues: Vec<SrtCue> = Vec::new();
+    let mut block: Vec<String> = Vec::new();
+
+    // 空行区切りで字幕を取り出します
+    for line in reader.lines() {
+        let line = line?;
+        let line = line.trim();
+
+        if line.is_empty() {
+            if let Some(cue) = parse_cue(&block) {
+                cues.push(cue);
+            }
+            block.clear();
+        } else {
+            block.push(line.to_string());
+        }
+    }
+    if let Some(cue) = parse_cue(&block) {
+        cues.push(cue);
+    }
+
+    let first = cues
+        .first()
+        .ok_or_else(|| anyhow::anyhow!("SRTファイルに位置情報がみつかりません"))?;
+
+    // 動画とのずれが出ないように、字幕の表示時刻を基準に日時を決めます
+    // (日時が記録されていない場合は 1970-01-01 00:00:00 からの経過時間とします)
+    let base: DateTime<Utc> = match first.date_time {
+        Some(dt) => arguments::from_local(&dt, tz).unwrap_or_else(|_| Utc.from_utc_datetime(&dt)),
+        None => Utc.timestamp(0, 0),
+    } - first.start;
+
+    let points = cues
+        .iter()
+        .map(|cue| TrackPoint {
+            ele: cue.ele,
+            ..TrackPoint::new(base + cue.start, cue.lat, cue.lng)
+        })
+        .collect();
+
+    Ok(TrackLog {
+        name: None,
+        segments: vec![points],
+        waypoints: Vec::new(),
+    })
+}
+
+// 字幕の間隔から求めた元の動画のフレームレート(間隔の中央値から求めます)
+pub fn frame_rate(track: &TrackLog) -> Option<f64> {
+    let mut intervals: Vec<i64> = track
+        .segments
+        .iter()
+        .flat_map(|segment| segment.windows(2))
+        .map(|pair| (pair[1].time - pair[0].time).num_milliseconds())
+        .filter(|x| *x > 0)
+        .collect();
+    if intervals.is_empty() {
+        return None;
+    }
+
+    intervals.sort_unstable();
+    Some(1000.0 / intervals[intervals.len() / 2] as f64)
+}
+
+fn parse_cue(block: &[String]) -> Option<SrtCue> {
+    // 番号行は省略されることがあるので、時刻行を探します
+    let time_index = block.iter().position(|line| line.contains("-->"))?;
+    let start = block[time_index].split("-->").next()?.trim();
+    let start = NaiveTime::parse_from_str(&start.replace(',', "."), "%H:%M:%S%.f").ok()?;
+    let start = start.signed_duration_since(NaiveTime::from_hms(0, 0, 0));
+
+    let text = remove_tags(&block[time_index + 1..].join(" "));
+
+    let (lat, lng) = match (
+        find_value(&text, &["latitude"]),
+        find_value(&text, &["longitude", "longtitude"]),
+    ) {
+        (Some(lat), Some(lng)) => (lat, lng),
+        _ => find_gps(&text)?,
+    };
+
+    // 0,0 は測位できていないフレーム
+    if lat == 0.0 && lng == 0.0 {
+        return None;
+    }
+
+    Some(SrtCue {
+        start,
+        date_time: find_date_time(&text),
+        lat,
+        lng,
+        ele: find_value(&text, &["abs_alt", "altitude", "rel_alt", "BAROMETER"]),
+    })
+}
+
+// <font ...> などのタグを取り除きます
+fn remove_tags(text: &str) -> String {
+    let mut result = String::new();
+    let mut in_tag = false;
+    for c in text.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' => in_tag = false,
+            _ if !in_tag => result.push(c),
+            _ => {}
+        }
+    }
+    result
+}
+
+// "キー: 値" 形式の数値を探します(候補のキーを先頭から順に試します)
+fn find_value(text: &str, keys: &[&str]) -> Option<f64> {
+    keys.iter().find_map(|key| {
+        let pos = text.find(key)?;
+        let rest = text[pos + key.len()..].trim_start();
+        let rest = rest.strip_prefix(':')?.trim_start();
+        let end = rest
+            .find(|c: char| !(c.is_ascii_digit() || c == '.' || c == '-' || c == '+'))
+            .unwrap_or(rest.len());
+
+        rest[..end].parse().ok()
+    })
+}
+
+// GPS(経度,緯度,高度)
+fn find_gps(text: &str) -> Option<(f64, f64)> {
+    let pos = text.find("GPS(")?;
+    let rest = &text[pos + 4..];
+    let rest = &rest[..rest.find(')')?];
+    let mut values = rest.split(',').map(|x| x.trim().parse::<f64>());
+
+    let lng = values.next()?.ok()?;
+    let lat = values.next()?.ok()?;
+    Some((lat, lng))
+}
+
+// 2023-05-20 10:11:12.345 / 2017.08.10 15:23:11
+fn find_date_time(text: &str) -> Option<NaiveDateTime> {
+    let words: Vec<&str> = text.split_whitespace().collect();
+
+    words.windows(2).find_map(|pair| {
+        let date = pair[0].replace('.', "-");
+        let time = pair[1].replace(',', ".");
+        NaiveDateTime::parse_from_str(&format!("{} {}", date, time), "%Y-%m-%d %H:%M:%S%.f").ok()
+    })
+}
+
+#[test]
+fn read_srt() {
+    let text = "1
+00:00:00,000 --> 00:00:00,033
+<font size=\"28\">FrameCnt: 1, DiffTime: 33ms
+2023-05-20 10:11:12.345
+[iso: 100] [shutter: 1/1000.0] [latitude: 35.744694] [longitude: 139.519397] [rel_alt: 1.200 abs_alt: 103.450] </font>
+
+2
+00:00:00,033 --> 00:00:00,066
+<font size=\"28\">FrameCnt: 2, DiffTime: 33ms
+2023-05-20 10:11:12.390
+[iso: 100] [shutter: 1/1000.0] [latitude: 35.744700] [longtitude : 139.519400] [rel_alt: 1.300 abs_alt: 103.550] </font>
+
+3
+00:00:00,066 --> 00:00:00,100
+HOME(139.5193,35.7446) 2023.05.20 10:11:12
+GPS(139.519410,35.744710,19) BAROMETER:1.4
+";
+    let log = read(text.as_bytes(), Some(chrono_tz::Asia::Tokyo)).unwrap();
+    let points = &log.segments[0];
+
+    assert_eq!(points.len(), 3);
+    assert_eq!(points[0].lat, 35.744694);
+    assert_eq!(points[0].ele, Some(103.45));
+    assert_eq!(points[1].lng, 139.5194);
+    assert_eq!(points[2].lat, 35.74471);
+    assert_eq!(points[2].ele, Some(1.4));
+
+    // 日時は --tz のタイムゾーンの日時とします
+    assert_eq!(points[0].time.to_rfc3339(), "2023-05-20T01:11:12.345+00:00");
+
+    // 字幕の表示時刻の間隔がそのまま日時の間隔になります
+    assert_eq!((points[1].time - points[0].time).num_milliseconds(), 33);
+    assert_eq!((points[2].time - points[0].time).num_milliseconds(), 66);
+    assert!((frame_rate(&log).unwrap() - 30.3).abs() < 0.1);
+}
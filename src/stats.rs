@@ -0,0 +1,269 @@
+// 走行の統計(距離、時間、速度、標高、心拍数・パワー、範囲)
+use crate::{
+    elevation,
+    track_point::{self, TrackLog, TrackPoint},
+};
+use chrono::{DateTime, Duration, Utc};
+use serde_json::json;
+use std::fmt;
+
+// 最高速度を求める最短の時間(秒)。GPSの揺れによる一瞬の飛びを数えないようにします
+const MAX_SPEED_WINDOW: f64 = 5.0;
+// 勾配を求める距離(m)
+const GRADE_DISTANCE: f64 = 100.0;
+
+#[derive(Debug, Clone, Copy)]
+pub struct StatsConfig {
+    // この速度(km/h)未満の区間は移動時間に含めません
+    pub moving_speed: f64,
+    // 獲得標高で数える最小の変化(m)
+    pub ele_threshold: f64,
+}
+
+// 平均と最大
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Summary {
+    pub avg: f64,
+    pub max: f64,
+}
+
+impl Summary {
+    fn from_values(values: impl Iterator<Item = f64>) -> Option<Self> {
+        let (count, sum, max) = values.fold((0, 0.0, f64::MIN), |(count, sum, max), x| (count + 1, sum + x, max.max(x)));
+        if count == 0 {
+            None
+        } else {
+            Some(Self {
+                avg: sum / count as f64,
+                max,
+            })
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Stats {
+    pub start: Option<DateTime<Utc>>,
+    pub end: Option<DateTime<Utc>>,
+    // 距離(m、セグメントの間の移動は含めません)
+    pub distance: f64,
+    pub elapsed: Duration,
+    pub moving: Duration,
+    // 移動中の平均速度と最高速度(km/h)
+    pub avg_speed: f64,
+    pub max_speed: f64,
+    pub ascent: f64,
+    pub descent: f64,
+    // 最大勾配(%)
+    pub max_grade: Option<f64>,
+    pub heart_rate: Option<Summary>,
+    pub power: Option<Summary>,
+    // 南端の緯度、西端の経度、北端の緯度、東端の経度
+    pub bounds: Option<(f64, f64, f64, f64)>,
+}
+
+impl Stats {
+    pub fn new(track: &TrackLog, config: &StatsConfig) -> Self {
+        let (start, end) = match track.time_range() {
+            Some((start, end)) => (Some(start), Some(end)),
+            None => (None, None),
+        };
+
+        let mut distance = 0.0;
+        let mut moving_seconds = 0.0;
+        let mut moving_distance = 0.0;
+        let mut max_speed: f64 = 0.0;
+        let (mut ascent, mut descent) = (0.0, 0.0);
+        let mut max_grade: Option<f64> = None;
+
+        for segment in &track.segments {
+            let mut distances = vec![0.0];
+            for pair in segment.windows(2) {
+                let length = track_point::distance(&pair[0], &pair[1]);
+                let seconds = (pair[1].time - pair[0].time).num_milliseconds() as f64 / 1000.0;
+                if seconds > 0.0 && length / seconds * 3.6 >= config.moving_speed {
+                    moving_seconds += seconds;
+                    moving_distance += length;
+                }
+                distances.push(distances[distances.len() - 1] + length);
+            }
+            distance += distances[distances.len() - 1];
+
+            // MAX_SPEED_WINDOW 秒以上離れたポイントまでの平均速度の最大
+            let mut to = 0;
+            for from in 0..segment.len() {
+                while to < segment.len() && seconds_between(&segment[from], &segment[to]) < MAX_SPEED_WINDOW {
+                    to += 1;
+                }
+                if to >= segment.len() {
+                    break;
+                }
+                let speed = (distances[to] - distances[from]) / seconds_between(&segment[from], &segment[to]) * 3.6;
+                max_speed = max_speed.max(speed);
+            }
+
+            if let Some(grade) = steepest_grade(segment, &distances) {
+                max_grade = Some(max_grade.map_or(grade, |max| max.max(grade)));
+            }
+
+            if let Some((segment_ascent, segment_descent)) =
+                elevation::cumulative_climb(segment, config.ele_threshold).last()
+            {
+                ascent += segment_ascent;
+                descent += segment_descent;
+            }
+        }
+
+        let points = || track.segments.iter().flatten();
+        let bounds = points().fold(None, |bounds: Option<(f64, f64, f64, f64)>, point| {
+            Some(match bounds {
+                Some((south, west, north, east)) => {
+                    (south.min(point.lat), west.min(point.lng), north.max(point.lat), east.max(point.lng))
+                }
+                None => (point.lat, point.lng, point.lat, point.lng),
+            })
+        });
+
+        Self {
+            start,
+            end,
+            distance,
+            elapsed: match (start, end) {
+                (Some(start), Some(end)) => end - start,
+                _ => Duration::zero(),
+            },
+            moving: Duration::milliseconds((moving_seconds * 1000.0).round() as i64),
+            avg_speed: if moving_seconds > 0.0 {
+                moving_distance / moving_seconds * 3.6
+            } else {
+                0.0
+            },
+            max_speed,
+            ascent,
+            descent,
+            max_grade,
+            heart_rate: Summary::from_values(points().filter_map(|point| point.hr)),
+            power: Summary::from_values(points().filter_map(|point| point.power)),
+            bounds,
+        }
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        let summary = |x: Option<Summary>| x.map(|x| json!({ "avg": x.avg, "max": x.max }));
+        json!({
+            "start": self.start.map(|x| x.to_rfc3339()),
+            "end": self.end.map(|x| x.to_rfc3339()),
+            "distance_m": self.distance,
+            "elapsed_s": self.elapsed.num_seconds(),
+            "moving_s": self.moving.num_seconds(),
+            "avg_speed_kmh": self.avg_speed,
+            "max_speed_kmh": self.max_speed,
+            "ascent_m": self.ascent,
+            "descent_m": self.descent,
+            "max_grade_percent": self.max_grade,
+            "heart_rate": summary(self.heart_rate),
+            "power": summary(self.power),
+            "bounds": self.bounds.map(|(south, west, north, east)| {
+                json!({ "south": south, "west": west, "north": north, "east": east })
+            }),
+        })
+    }
+}
+
+// GRADE_DISTANCE m 以上離れたポイントまでの勾配(%)の最大(distances はポイントごとの累積距離)
+pub fn steepest_grade(points: &[TrackPoint], distances: &[f64]) -> Option<f64> {
+    let mut max_grade: Option<f64> = None;
+    let mut to = 0;
+    for from in 0..points.len() {
+        while to < points.len() && distances[to] - distances[from] < GRADE_DISTANCE {
+            to += 1;
+        }
+        if to >= points.len() {
+            break;
+        }
+        if let (Some(ele_from), Some(ele_to)) = (points[from].ele, points[to].ele) {
+            let grade = (ele_to - ele_from) / (distances[to] - distances[from]) * 100.0;
+            max_grade = Some(max_grade.map_or(grade, |max| max.max(grade)));
+        }
+    }
+    max_grade
+}
+
+fn seconds_between(a: &TrackPoint, b: &TrackPoint) -> f64 {
+    (b.time - a.time).num_milliseconds() as f64 / 1000.0
+}
+
+fn format_duration(duration: Duration) -> String {
+    format!(
+        "{}:{:02}:{:02}",
+        duration.num_hours(),
+        duration.num_minutes() % 60,
+        duration.num_seconds() % 60
+    )
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "距離: {:.2} km", self.distance / 1000.0)?;
+        writeln!(f, "経過時間: {}", format_duration(self.elapsed))?;
+        writeln!(f, "移動時間: {}", format_duration(self.moving))?;
+        writeln!(f, "平均速度: {:.1} km/h (移動中)", self.avg_speed)?;
+        writeln!(f, "最高速度: {:.1} km/h", self.max_speed)?;
+        writeln!(f, "獲得標高: +{:.0} m / -{:.0} m", self.ascent, self.descent)?;
+        if let Some(grade) = self.max_grade {
+            writeln!(f, "最大勾配: {:.1} %", grade)?;
+        }
+        if let Some(hr) = self.heart_rate {
+            writeln!(f, "心拍数: 平均 {:.0} / 最大 {:.0} bpm", hr.avg, hr.max)?;
+        }
+        if let Some(power) = self.power {
+            writeln!(f, "パワー: 平均 {:.0} / 最大 {:.0} W", power.avg, power.max)?;
+        }
+        if let Some((south, west, north, east)) = self.bounds {
+            writeln!(f, "範囲: {:.5},{:.5},{:.5},{:.5}", south, west, north, east)?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn ride_stats() {
+    use crate::track_point::{test_point, test_track};
+
+    // 1秒ごとに約10m北へ進み(時速36km)、途中で60秒止まる。後半の200mは10%の上り
+    let step = 10.0 / 111_195.0;
+    let mut points: Vec<TrackPoint> = (0..=50)
+        .map(|i| TrackPoint {
+            ele: Some(100.0),
+            hr: Some(120.0 + i as f64),
+            ..test_point(i, 35.0 + i as f64 * step)
+        })
+        .collect();
+    points.extend((0..=20).map(|i| TrackPoint {
+        ele: Some(100.0 + i as f64),
+        ..test_point(110 + i, 35.0 + (50 + i) as f64 * step)
+    }));
+    let track = test_track(points);
+
+    let stats = Stats::new(
+        &track,
+        &StatsConfig {
+            moving_speed: 2.0,
+            ele_threshold: 5.0,
+        },
+    );
+    assert!((stats.distance - 700.0).abs() < 1.0);
+    assert_eq!(stats.elapsed, Duration::seconds(130));
+    assert_eq!(stats.moving, Duration::seconds(70));
+    assert!((stats.avg_speed - 36.0).abs() < 0.1);
+    assert!((stats.max_speed - 36.0).abs() < 0.1);
+    assert_eq!(stats.ascent, 20.0);
+    assert!((stats.max_grade.unwrap() - 10.0).abs() < 0.1);
+    assert_eq!(stats.heart_rate, Some(Summary { avg: 145.0, max: 170.0 }));
+    assert_eq!(stats.power, None);
+
+    let json = stats.to_json();
+    assert_eq!(json["elapsed_s"], 130);
+    assert!(json["power"].is_null());
+    assert!(stats.to_string().contains("移動時間: 0:01:10"));
+}
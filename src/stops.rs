@@ -0,0 +1,151 @@
+// 停止(休憩や信号待ち)の検出
+use crate::track_point::{self, TrackLog, TrackPoint};
+use chrono::{DateTime, Duration, Utc};
+use std::str::FromStr;
+
+// 停止区間の再生方法
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StopMode {
+    // そのまま再生します
+    None,
+    // 停止区間を飛ばします
+    Skip,
+    // "PAUSED 12 MIN" と短く表示して飛ばします
+    Card,
+    // 早送りで再生します
+    Fast,
+}
+
+impl FromStr for StopMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(StopMode::None),
+            "skip" => Ok(StopMode::Skip),
+            "card" => Ok(StopMode::Card),
+            "fast" => Ok(StopMode::Fast),
+            _ => Err(anyhow::anyhow!("停止区間の再生方法は none, skip, card, fast のいずれかです")),
+        }
+    }
+}
+
+// 停止区間
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Stop {
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    // 停止した位置(停止区間の最初のポイント)
+    pub point: TrackPoint,
+}
+
+impl Stop {
+    pub fn duration(&self) -> Duration {
+        self.end - self.start
+    }
+
+    pub fn contains(&self, time: DateTime<Utc>) -> bool {
+        self.start <= time && time < self.end
+    }
+}
+
+// 速度が speed (km/h) 未満の状態が min_duration 以上続いた区間を停止とみなします
+pub fn detect_stops(track: &TrackLog, speed: f64, min_duration: Duration) -> Vec<Stop> {
+    let mut stops = Vec::new();
+
+    for segment in &track.segments {
+        // 停止中の区間の開始位置
+        let mut start: Option<usize> = None;
+
+        for index in 1..=segment.len() {
+            let moving = match segment.get(index) {
+                Some(point) => {
+                    let prev = &segment[index - 1];
+                    let seconds = (point.time - prev.time).num_milliseconds() as f64 / 1000.0;
+                    seconds <= 0.0 || track_point::distance(prev, point) / seconds * 3.6 >= speed
+                }
+                // セグメントの終わり
+                None => true,
+            };
+
+            match (moving, start) {
+                (false, None) => start = Some(index - 1),
+                (true, Some(first)) => {
+                    let (first, last) = (&segment[first], &segment[index - 1]);
+                    if last.time - first.time >= min_duration {
+                        stops.push(Stop {
+                            start: first.time,
+                            end: last.time,
+                            point: *first,
+                        });
+                    }
+                    start = None;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    stops
+}
+
+// "PAUSED 12 MIN" のような停止時間の表示
+pub fn pause_text(duration: Duration) -> String {
+    let minutes = duration.num_minutes();
+    if minutes < 1 {
+        format!("PAUSED {} SEC", duration.num_seconds())
+    } else if minutes < 60 {
+        format!("PAUSED {} MIN", minutes)
+    } else {
+        format!("PAUSED {}H {:02}MIN", minutes / 60, minutes % 60)
+    }
+}
+
+#[test]
+fn detect_and_skip_stops() {
+    use crate::track_point::{test_point, test_time, test_track};
+    use crate::track_point::{Frame, FrameEvent, Playback, TrackIter};
+
+    // 100秒走って、300秒止まり、また100秒走る(GPSの揺れで少しだけ動く)
+    let mut points: Vec<TrackPoint> = (0..=10).map(|i| test_point(i * 10, 35.0 + i as f64 * 0.001)).collect();
+    points.extend((1..30).map(|i| test_point(100 + i * 10, 35.01 + (i % 2) as f64 * 0.00001)));
+    points.extend((0..=10).map(|i| test_point(400 + i * 10, 35.01 + i as f64 * 0.001)));
+    let track = test_track(points);
+
+    let stops = detect_stops(&track, 2.0, Duration::seconds(60));
+    assert_eq!(stops.len(), 1);
+    assert_eq!(stops[0].start, test_time(100));
+    assert_eq!(stops[0].end, test_time(400));
+    assert_eq!(pause_text(stops[0].duration()), "PAUSED 5 MIN");
+
+    // 飛ばすと停止の300秒分のフレームがなくなる
+    let count = TrackIter::get_iter(&track, 1, None, None)
+        .with_stops(stops.clone(), StopMode::Skip, 10.0)
+        .count();
+    assert_eq!(count, 201);
+
+    // カードの場合は2秒分の表示が入る
+    let frames: Vec<Frame> = TrackIter::get_iter(&track, 1, None, None)
+        .with_stops(stops.clone(), StopMode::Card, 10.0)
+        .collect::<anyhow::Result<_>>()
+        .unwrap();
+    assert_eq!(frames.len(), 203);
+    assert!(matches!(frames[100].event, Some(FrameEvent::Paused { .. })));
+    assert_eq!(frames[102].point.time, stops[0].end);
+
+    // 早送りは停止中だけ10倍で進む
+    let count = TrackIter::get_iter(&track, 1, None, None)
+        .with_stops(stops.clone(), StopMode::Fast, 10.0)
+        .count();
+    assert_eq!(count, 231);
+
+    // 長さを指定した場合は、停止区間の扱いを含めて20秒(30fpsで600フレーム)に収める
+    for mode in [StopMode::Skip, StopMode::Card, StopMode::Fast] {
+        let iter = TrackIter::get_iter(&track, 30, None, None)
+            .with_stops(stops.clone(), mode, 10.0)
+            .with_playback(Playback::Duration(Duration::seconds(20)));
+        assert!((iter.video_length() - 20.0).abs() < 0.1, "{:?} {}", mode, iter.video_length());
+        let count = iter.count();
+        assert!((599..=602).contains(&count), "{:?} {}", mode, count);
+    }
+}
@@ -0,0 +1,143 @@
+// 時刻のないトラック・ルート(ルート作成サービスで作った計画ルートなど)に、想定した速度で時刻を付けます
+use crate::track_point::{self, TrackPoint};
+use chrono::{DateTime, Duration, Utc};
+use std::str::FromStr;
+
+// 勾配を調べる前後の距離(m)
+const GRADE_DISTANCE: f64 = 50.0;
+// 上り勾配1%あたりに遅くする割合(10% の上りで半分の速度)
+const UPHILL_FACTOR: f64 = 10.0;
+// 下り勾配1%あたりに速くする割合と、その上限の倍率
+const DOWNHILL_FACTOR: f64 = 5.0;
+const DOWNHILL_MAX: f64 = 1.5;
+// 勾配の上限(標高データの誤差で極端な速度にならないようにします)
+const MAX_GRADE: f64 = 0.2;
+
+// 速度の付け方
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SpeedModel {
+    // 一定の速度
+    Constant,
+    // 勾配に合わせて上りは遅く、下りは速くします(標高がない場合は一定)
+    Grade,
+}
+
+impl FromStr for SpeedModel {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "constant" => Ok(SpeedModel::Constant),
+            "grade" => Ok(SpeedModel::Grade),
+            _ => Err(anyhow::anyhow!("速度の付け方は constant, grade のいずれかです")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct TimeSynthesis {
+    // 平地での速度(km/h)
+    pub speed: f64,
+    pub model: SpeedModel,
+    // 出発時刻(指定がなければgpxのメタデータの時刻、それもなければ現在時刻)
+    pub start: Option<DateTime<Utc>>,
+}
+
+impl TimeSynthesis {
+    // ポイントに start からの時刻を付けます(元の時刻は無視します)
+    pub fn synthesize(&self, points: &[gpx::Waypoint], start: DateTime<Utc>) -> Vec<TrackPoint> {
+        let mut result: Vec<TrackPoint> = points
+            .iter()
+            .map(|point| TrackPoint {
+                ele: point.elevation,
+                ..TrackPoint::new(start, point.point().lat(), point.point().lng())
+            })
+            .collect();
+
+        let mut distances = vec![0.0];
+        for pair in result.windows(2) {
+            distances.push(distances[distances.len() - 1] + track_point::distance(&pair[0], &pair[1]));
+        }
+        let grades = match self.model {
+            SpeedModel::Constant => vec![0.0; result.len()],
+            SpeedModel::Grade => grades(&result, &distances),
+        };
+
+        let mut seconds = 0.0;
+        for index in 1..result.len() {
+            let grade = (grades[index - 1] + grades[index]) / 2.0;
+            let speed = self.speed / 3.6 * speed_factor(grade);
+            seconds += (distances[index] - distances[index - 1]) / speed;
+            result[index].time = start + Duration::milliseconds((seconds * 1000.0).round() as i64);
+        }
+
+        result
+    }
+}
+
+// ポイントごとの前後 GRADE_DISTANCE m の勾配(標高がない場合は 0)
+fn grades(points: &[TrackPoint], distances: &[f64]) -> Vec<f64> {
+    (0..points.len())
+        .map(|index| {
+            let from = distances.partition_point(|d| *d < distances[index] - GRADE_DISTANCE);
+            let to = distances
+                .partition_point(|d| *d <= distances[index] + GRADE_DISTANCE)
+                .saturating_sub(1);
+            let length = distances[to] - distances[from];
+
+            match (points[from].ele, points[to].ele) {
+                (Some(ele_from), Some(ele_to)) if length > 0.0 => {
+                    ((ele_to - ele_from) / length).clamp(-MAX_GRADE, MAX_GRADE)
+                }
+                _ => 0.0,
+            }
+        })
+        .collect()
+}
+
+// 平地の速度に対する倍率
+fn speed_factor(grade: f64) -> f64 {
+    if grade >= 0.0 {
+        1.0 / (1.0 + UPHILL_FACTOR * grade)
+    } else {
+        (1.0 - DOWNHILL_FACTOR * grade).min(DOWNHILL_MAX)
+    }
+}
+
+#[test]
+fn synthesize_route() {
+    use chrono::TimeZone;
+
+    // 北へ約1km(10m間隔)、後半の500mは5%の上り
+    let points: Vec<gpx::Waypoint> = (0..=100)
+        .map(|i| {
+            let mut point = gpx::Waypoint::new(geo_types::Point::new(139.5, 35.0 + i as f64 * 10.0 / 111_195.0));
+            point.elevation = Some(if i <= 50 { 100.0 } else { 100.0 + (i - 50) as f64 * 0.5 });
+            point
+        })
+        .collect();
+    let start = Utc.ymd(2020, 8, 1).and_hms(0, 0, 0);
+
+    // 時速20kmで1kmは180秒
+    let constant = TimeSynthesis {
+        speed: 20.0,
+        model: SpeedModel::Constant,
+        start: None,
+    };
+    let result = constant.synthesize(&points, start);
+    assert_eq!(result[0].time, start);
+    assert!(((result[100].time - start).num_seconds() - 180).abs() <= 1);
+    assert_eq!(result[100].ele, Some(125.0));
+
+    // 上りは1.5倍の時間がかかる
+    let grade = TimeSynthesis {
+        model: SpeedModel::Grade,
+        ..constant
+    };
+    let result = grade.synthesize(&points, start);
+    let flat = (result[40].time - result[0].time).num_seconds();
+    let climb = (result[100].time - result[60].time).num_seconds();
+    assert_eq!(flat, 72);
+    assert_eq!(climb, 108);
+    assert!(result.windows(2).all(|pair| pair[0].time < pair[1].time));
+}
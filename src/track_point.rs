@@ -1,5 +1,11 @@
+use anyhow::Result;
 use chrono::{DateTime,  Duration,  Utc};
-use gpx::Track;
+use crate::adaptive::SpeedProfile;
+use crate::extensions::SensorData;
+use crate::smoothing;
+use crate::stops::{Stop, StopMode};
+use gpx::{Route, Track};
+use std::str::FromStr;
 
 pub struct GroupIterater<T:Iterator> {
     iterator: T,
@@ -43,61 +49,605 @@ impl<T> Iterator for GroupIterater<T> where T:Iterator {
 
 
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub struct TrackPoint {
     pub time: DateTime<Utc>,
     pub lat: f64,
     pub lng: f64,
+    pub ele: Option<f64>,
+    pub hr: Option<f64>,
+    pub cad: Option<f64>,
+    pub power: Option<f64>,
+    pub temp: Option<f64>,
+}
+
+impl TrackPoint {
+    // 日時と位置だけのポイントを作成します
+    pub fn new(time: DateTime<Utc>, lat: f64, lng: f64) -> Self {
+        Self {
+            time,
+            lat,
+            lng,
+            ele: None,
+            hr: None,
+            cad: None,
+            power: None,
+            temp: None,
+        }
+    }
+}
+
+// 地図上に表示する地点(IGCのタスクのターンポイントなど)
+#[derive(Debug, Clone)]
+pub struct Waypoint {
+    pub name: String,
+    pub lat: f64,
+    pub lng: f64,
+}
+
+// 入力ファイルの形式によらないトラックデータ
+#[derive(Debug, Clone, Default)]
+pub struct TrackLog {
+    pub name: Option<String>,
+    pub segments: Vec<Vec<TrackPoint>>,
+    pub waypoints: Vec<Waypoint>,
+}
+
+impl TrackLog {
+    // gpxのトラックから、extensionsのセンサーデータ(セグメント > ポイント順)を含めて変換します
+    pub fn from_gpx_track(track: &Track, sensors: &[Vec<SensorData>]) -> Self {
+        let segments = track
+            .segments
+            .iter()
+            .enumerate()
+            .map(|(index, segment)| {
+                let sensors = sensors.get(index).map(|x| x.as_slice()).unwrap_or(&[]);
+                Self::from_gpx_points(&segment.points, sensors)
+            })
+            .collect();
+
+        Self {
+            name: track.name.clone(),
+            segments,
+            waypoints: Vec::new(),
+        }
+    }
+
+    // gpxのルートから変換します
+    pub fn from_gpx_route(route: &Route) -> Self {
+        Self {
+            name: route.name.clone(),
+            segments: vec![Self::from_gpx_points(&route.points, &[])],
+            waypoints: Vec::new(),
+        }
+    }
+
+    // gpxのトラックに変換します(センサーデータは含みません)
+    pub fn to_gpx_track(&self) -> Track {
+        let mut track = Track::new();
+        track.name = self.name.clone();
+        track.segments = self
+            .segments
+            .iter()
+            .map(|segment| {
+                let mut result = gpx::TrackSegment::new();
+                result.points = segment
+                    .iter()
+                    .map(|point| {
+                        let mut waypoint = gpx::Waypoint::new(geo_types::Point::new(point.lng, point.lat));
+                        waypoint.elevation = point.ele;
+                        waypoint.time = Some(point.time);
+                        waypoint
+                    })
+                    .collect();
+                result
+            })
+            .collect();
+
+        track
+    }
+
+    // 日時のあるポイントだけを取得します
+    fn from_gpx_points(points: &[gpx::Waypoint], sensors: &[SensorData]) -> Vec<TrackPoint> {
+        points
+            .iter()
+            .enumerate()
+            .filter(|(_, item)| item.time.is_some())
+            .map(|(index, point)| {
+                let sensor = sensors.get(index).copied().unwrap_or_default();
+
+                TrackPoint {
+                    ele: point.elevation,
+                    hr: sensor.hr,
+                    cad: sensor.cad,
+                    power: sensor.power,
+                    temp: sensor.temp,
+                    ..TrackPoint::new(point.time.unwrap(), point.point().lat(), point.point().lng())
+                }
+            })
+            .collect()
+    }
+
+    // 複数のトラックをセグメント単位で時刻順に連結します
+    pub fn concat(tracks: Vec<TrackLog>) -> Self {
+        let mut segments: Vec<Vec<TrackPoint>> = tracks
+            .into_iter()
+            .flat_map(|track| track.segments)
+            .filter(|segment| !segment.is_empty())
+            .collect();
+        segments.sort_by_key(|segment| segment[0].time);
+
+        Self {
+            name: None,
+            segments,
+            waypoints: Vec::new(),
+        }
+    }
+
+    // 最初と最後のポイントの日時
+    pub fn time_range(&self) -> Option<(DateTime<Utc>, DateTime<Utc>)> {
+        let mut points = self.segments.iter().flatten();
+        let first = points.next()?;
+        let last = points.last().unwrap_or(first);
+
+        Some((first.time, last.time))
+    }
+
+    // 時刻が逆行しているポイントを扱い方に従って並べ替え・削除します
+    pub fn order_by_time(&mut self, order: TimeOrder) -> Result<()> {
+        match order {
+            TimeOrder::Error => {
+                let points: Vec<&TrackPoint> = self.segments.iter().flatten().collect();
+                if let Some(pair) = points.windows(2).find(|pair| pair[1].time < pair[0].time) {
+                    return Err(anyhow::anyhow!(
+                        "時刻が逆行しています: {} の次が {}",
+                        pair[0].time.to_rfc3339(),
+                        pair[1].time.to_rfc3339()
+                    ));
+                }
+            }
+            TimeOrder::Sort => {
+                for segment in &mut self.segments {
+                    segment.sort_by_key(|point| point.time);
+                }
+                self.segments.retain(|segment| !segment.is_empty());
+                self.segments.sort_by_key(|segment| segment[0].time);
+
+                // 時間が重なっているセグメントは一つにまとめて並べ替えます
+                let mut merged: Vec<Vec<TrackPoint>> = Vec::new();
+                for segment in self.segments.drain(..) {
+                    match merged.last_mut() {
+                        Some(last) if segment[0].time < last[last.len() - 1].time => {
+                            last.extend(segment);
+                            last.sort_by_key(|point| point.time);
+                        }
+                        _ => merged.push(segment),
+                    }
+                }
+                self.segments = merged;
+            }
+            TimeOrder::Drop => {
+                let mut last: Option<DateTime<Utc>> = None;
+                for segment in &mut self.segments {
+                    segment.retain(|point| {
+                        let keep = last.is_none_or(|time| point.time >= time);
+                        if keep {
+                            last = Some(point.time);
+                        }
+                        keep
+                    });
+                }
+                self.segments.retain(|segment| !segment.is_empty());
+            }
+        }
+
+        Ok(())
+    }
 }
 
-pub struct TrackIter<'a> {
-    points: Box<(dyn Iterator<Item = TrackPoint> + 'a)>,
+// 2点間の距離(m、ハバーサイン公式)
+pub fn distance(a: &TrackPoint, b: &TrackPoint) -> f64 {
+    const EARTH_RADIUS: f64 = 6_371_000.0;
+
+    let (lat1, lat2) = (a.lat.to_radians(), b.lat.to_radians());
+    let dlat = lat2 - lat1;
+    let dlng = (b.lng - a.lng).to_radians();
+
+    let h = (dlat / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * (dlng / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS * h.sqrt().asin()
+}
+
+// ギャップ(信号の途絶や記録の一時停止)の扱い
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GapMode {
+    // ギャップの時間を飛ばします
+    Skip,
+    // ギャップの間はその場に留まり、再開した位置へ移動します
+    Jump,
+    // ギャップをまたいで直線で補間します
+    Interpolate,
+}
+
+impl FromStr for GapMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "skip" => Ok(GapMode::Skip),
+            "jump" => Ok(GapMode::Jump),
+            "interpolate" => Ok(GapMode::Interpolate),
+            _ => Err(anyhow::anyhow!("ギャップの扱いは skip, jump, interpolate のいずれかです")),
+        }
+    }
+}
+
+// 時刻が逆行しているポイントの扱い
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TimeOrder {
+    // 時刻順に並べ替えます(時間が重なっているセグメントはまとめます)
+    Sort,
+    // 直前のポイントより前の時刻のポイントを捨てます
+    Drop,
+    // エラーにします
+    Error,
+}
+
+impl FromStr for TimeOrder {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sort" => Ok(TimeOrder::Sort),
+            "drop" => Ok(TimeOrder::Drop),
+            "error" => Ok(TimeOrder::Error),
+            _ => Err(anyhow::anyhow!("時刻の逆行の扱いは sort, drop, error のいずれかです")),
+        }
+    }
+}
+
+// 再生速度
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Playback {
+    // 動画1秒あたりのトラックの秒数(1.0で実時間)
+    Speed(f64),
+    // 対象範囲全体をこの長さの動画に収めます
+    Duration(Duration),
+    // 距離基準: 動画1秒あたりの距離(m)
+    Distance(f64),
+    // 距離基準: 対象範囲全体をこの長さの動画に収めます
+    DistanceDuration(Duration),
+}
+
+// フレームに付随する出来事
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FrameEvent {
+    // 信号途絶中(progress はギャップ内の経過割合 0.0〜1.0)
+    SignalLost { progress: f64 },
+    // 停止区間のカード表示中(duration は停止時間、progress はカード表示の経過割合)
+    Paused { duration: Duration, progress: f64 },
+}
+
+// 動画の一フレーム分の情報
+#[derive(Debug, Clone, Copy)]
+pub struct Frame {
+    pub point: TrackPoint,
+    pub event: Option<FrameEvent>,
+}
+
+// 停止区間のカードを表示する秒数
+const STOP_CARD_SECONDS: usize = 2;
+
+pub struct TrackIter {
+    points: Vec<TrackPoint>,
+    segment_starts: Vec<bool>,
+    index: usize,
     fps: usize,
     start_dt: Option<DateTime<Utc>>,
 
     end_dt: Option<DateTime<Utc>>,
 
+    gap_mode: GapMode,
+    max_gap: Option<Duration>,
+    // 動画1秒あたりのトラックの秒数(距離基準の場合は距離(m))
+    speed: f64,
+    // 距離基準の場合の各ポイントまでの累積距離(時間基準の場合は空)
+    distances: Vec<f64>,
+
+    stops: Vec<Stop>,
+    stop_mode: StopMode,
+    // 停止区間の早送りの倍率
+    stop_speedup: f64,
+    // 停止区間のカードを表示したフレーム数
+    card_frame: usize,
+    // 区間ごとの再生速度の倍率
+    speed_profile: Option<SpeedProfile>,
+    // ポイントの間をスプラインで補間するかどうか
+    spline: bool,
+
     current: Option<DateTime<Utc>>,
-    current_fps: usize,
+    // current からの経過時間(ミリ秒)、距離基準の場合は先頭からの距離(m)
+    elapsed: f64,
     point_prev: Option<TrackPoint>,
     point_next: Option<TrackPoint>,
 }
 
-impl<'a> TrackIter<'a> {
+impl TrackIter {
     pub fn get_iter(
-        track: &'a Track,
+        track: &TrackLog,
         fps: usize,
         start_dt: Option<DateTime<Utc>>,
         end_dt: Option<DateTime<Utc>>,
     ) -> Self {
-        // 日時のあるポイントだけを取得します
-        let points = track
+        // 全ポイントを並べ、セグメントの先頭に印を付けます
+        let points: Vec<TrackPoint> = track.segments.iter().flatten().copied().collect();
+        let segment_starts = track
             .segments
             .iter()
-            .flat_map(|item| {
-                let xx = item.clone();
-                xx.points.into_iter().clone()
-            })
-            .filter(|item| item.time.is_some())
-            .map(|point| TrackPoint {
-                time: point.time.unwrap(),
-                lat: point.point().lat(),
-                lng: point.point().lng(),
-            });
-
-        let iter = points.into_iter();
+            .flat_map(|segment| (0..segment.len()).map(|index| index == 0))
+            .collect();
+
         TrackIter {
-            points: Box::new(iter),
+            points,
+            segment_starts,
+            index: 0,
             fps,
             start_dt,
             end_dt,
+            gap_mode: GapMode::Skip,
+            max_gap: None,
+            speed: 1.0,
+            distances: Vec::new(),
+            stops: Vec::new(),
+            stop_mode: StopMode::None,
+            stop_speedup: 1.0,
+            card_frame: 0,
+            speed_profile: None,
+            spline: false,
             current: None,
-            current_fps: 0,
+            elapsed: 0.0,
             point_next: None,
             point_prev: None,
         }
     }
 
+    // ギャップの扱いを設定します(max_gap を超える間隔もギャップとみなします)
+    pub fn with_gap(mut self, gap_mode: GapMode, max_gap: Option<Duration>) -> Self {
+        self.gap_mode = gap_mode;
+        self.max_gap = max_gap;
+        self
+    }
+
+    // 再生速度を設定します(長さに合わせる場合は、ギャップ・停止区間・区間ごとの倍率で長さが変わるので
+    // with_gap、with_stops、with_speed_profile の後に呼びます)
+    pub fn with_playback(mut self, playback: Playback) -> Self {
+        if let Playback::Distance(_) | Playback::DistanceDuration(_) = playback {
+            self.distances = self.cumulative_distances();
+        }
+
+        // 長さに合わせる場合の速度(停止区間のカードは速度によらず一定の長さです)
+        let fit = |length: f64, duration: Duration| {
+            let duration = duration.num_milliseconds() as f64 / 1000.0 - self.card_length();
+            if length > 0.0 && duration > 0.0 {
+                Some(length / duration)
+            } else {
+                None
+            }
+        };
+
+        self.speed = match playback {
+            Playback::Speed(speed) | Playback::Distance(speed) => speed,
+            Playback::Duration(duration) => fit(self.playback_amount(), duration).unwrap_or(1.0),
+            Playback::DistanceDuration(duration) => fit(self.playback_amount(), duration).unwrap_or(100.0),
+        };
+        self
+    }
+
+    // 停止区間の再生方法を設定します(時間基準の再生のみ、距離基準では停止中はほとんど進みません)
+    pub fn with_stops(mut self, stops: Vec<Stop>, stop_mode: StopMode, stop_speedup: f64) -> Self {
+        self.stops = stops;
+        self.stop_mode = stop_mode;
+        self.stop_speedup = stop_speedup;
+        self
+    }
+
+    // 区間の様子に合わせて再生速度を変えます
+    pub fn with_speed_profile(mut self, speed_profile: SpeedProfile) -> Self {
+        self.speed_profile = Some(speed_profile);
+        self
+    }
+
+    // ポイントの間を直線ではなくスプラインで補間します
+    pub fn with_spline(mut self, spline: bool) -> Self {
+        self.spline = spline;
+        self
+    }
+
+    // 直線で補間した位置をスプラインの位置に置き換えます
+    fn spline_position(&self, mut point: TrackPoint, prev: &TrackPoint, next: &TrackPoint) -> TrackPoint {
+        // next は index - 1 番目、prev はその前のポイント
+        let index = self.index;
+        if index < 2
+            || prev.time >= next.time
+            || self.points[index - 2].time != prev.time
+            || self.is_gap_before(index - 1)
+        {
+            return point;
+        }
+
+        let p0 = if index >= 3 && !self.is_gap_before(index - 2) {
+            Some(&self.points[index - 3])
+        } else {
+            None
+        };
+        let p3 = if index < self.points.len() && !self.is_gap_before(index) {
+            Some(&self.points[index])
+        } else {
+            None
+        };
+
+        let ratio = (point.time - prev.time).num_milliseconds() as f64
+            / (next.time - prev.time).num_milliseconds() as f64;
+        let (lat, lng) = smoothing::catmull_rom(p0, prev, next, p3, ratio);
+        point.lat = lat;
+        point.lng = lng;
+        point
+    }
+
+    fn stop_at(&self, time: DateTime<Utc>) -> Option<Stop> {
+        if self.stop_mode == StopMode::None || self.is_by_distance() {
+            return None;
+        }
+
+        self.stops.iter().find(|stop| stop.contains(time)).copied()
+    }
+
+    // 距離基準で再生するかどうか
+    pub fn is_by_distance(&self) -> bool {
+        !self.distances.is_empty()
+    }
+
+    // 動画1秒あたりのトラックの秒数(距離基準の場合は距離(m))
+    pub fn speed(&self) -> f64 {
+        self.speed
+    }
+
+    // 動画のおおよその長さ(秒)
+    pub fn video_length(&self) -> f64 {
+        self.playback_amount() / self.speed + self.card_length()
+    }
+
+    // 指定時刻の再生速度の倍率(停止区間の早送りと区間ごとの倍率)
+    fn rate_at(&self, time: DateTime<Utc>) -> f64 {
+        let speedup = match self.stop_at(time) {
+            Some(_) if self.stop_mode == StopMode::Fast => self.stop_speedup,
+            _ => 1.0,
+        };
+        let factor = self.speed_profile.as_ref().map_or(1.0, |profile| profile.factor_at(time));
+        speedup * factor
+    }
+
+    // 再生される範囲の始まりと終わり
+    fn playback_range(&self) -> Option<(DateTime<Utc>, DateTime<Utc>)> {
+        let (first, last) = (self.points.first()?.time, self.points.last()?.time);
+        let start = self.start_dt.map_or(first, |dt| dt.max(first));
+        let end = self.end_dt.map_or(last, |dt| dt.min(last));
+        Some((start, end))
+    }
+
+    // 停止区間のカードを表示する時間(秒)
+    fn card_length(&self) -> f64 {
+        if self.stop_mode != StopMode::Card || self.is_by_distance() {
+            return 0.0;
+        }
+
+        let (start, end) = match self.playback_range() {
+            Some(range) => range,
+            None => return 0.0,
+        };
+        let count = self.stops.iter().filter(|stop| start <= stop.start && stop.start < end).count();
+        (count * STOP_CARD_SECONDS) as f64
+    }
+
+    // 再生される範囲のトラック上の時間(秒、距離基準の場合は距離(m))を再生速度の倍率で割ったもの
+    // (飛ばすギャップと停止区間は含みません)。これを speed で割ると、カード以外の動画の長さになります
+    fn playback_amount(&self) -> f64 {
+        let (start, end) = match self.playback_range() {
+            Some(range) => range,
+            None => return 0.0,
+        };
+        let skip_stops = matches!(self.stop_mode, StopMode::Skip | StopMode::Card);
+
+        let mut amount = 0.0;
+        for index in 1..self.points.len() {
+            if self.gap_mode == GapMode::Skip && self.is_gap_before(index) {
+                continue;
+            }
+
+            // 倍率が変わるので1秒ごとに区切って、中点の倍率で割ります
+            let mut from = self.points[index - 1].time.max(start);
+            let to = self.points[index].time.min(end);
+            while from < to {
+                let next = (from + Duration::seconds(1)).min(to);
+                let middle = from + (next - from) / 2;
+                if !(skip_stops && self.stop_at(middle).is_some()) {
+                    let length = if self.is_by_distance() {
+                        self.distance_at_time(next) - self.distance_at_time(from)
+                    } else {
+                        (next - from).num_milliseconds() as f64 / 1000.0
+                    };
+                    amount += length / self.rate_at(middle);
+                }
+                from = next;
+            }
+        }
+
+        amount
+    }
+
+    // 各ポイントまでの累積距離(飛ばすギャップの区間は数えません)
+    fn cumulative_distances(&self) -> Vec<f64> {
+        let mut total = 0.0;
+        let mut distances = Vec::with_capacity(self.points.len());
+
+        for (index, point) in self.points.iter().enumerate() {
+            if index > 0 && !(self.gap_mode == GapMode::Skip && self.is_gap_before(index)) {
+                total += distance(&self.points[index - 1], point);
+            }
+            distances.push(total);
+        }
+
+        distances
+    }
+
+    // 指定時刻までの累積距離(距離基準の場合のみ)
+    fn distance_at_time(&self, time: DateTime<Utc>) -> f64 {
+        let distances = &self.distances;
+        let index = self.points.partition_point(|point| point.time < time);
+        if index == 0 || distances.is_empty() {
+            return 0.0;
+        }
+        if index >= self.points.len() {
+            return distances[distances.len() - 1];
+        }
+
+        let (prev, next) = (&self.points[index - 1], &self.points[index]);
+        let ratio = (time - prev.time).num_milliseconds() as f64
+            / (next.time - prev.time).num_milliseconds() as f64;
+        distances[index - 1] + (distances[index] - distances[index - 1]) * ratio
+    }
+
+    // 指定した累積距離に到達する時刻(距離が進まない停止中や飛ばすギャップは通り過ぎます)
+    fn time_at_distance(&self, target: f64) -> Option<DateTime<Utc>> {
+        let index = self.distances.partition_point(|distance| *distance < target);
+        if index >= self.points.len() {
+            return None;
+        }
+        if index == 0 {
+            return Some(self.points[0].time);
+        }
+
+        let (prev, next) = (&self.points[index - 1], &self.points[index]);
+        let ratio = (target - self.distances[index - 1]) / (self.distances[index] - self.distances[index - 1]);
+        let millis = ((next.time - prev.time).num_milliseconds() as f64 * ratio).round() as i64;
+        Some(prev.time + Duration::milliseconds(millis))
+    }
+
+    // index番目のポイントの直前がギャップかどうか
+    fn is_gap_before(&self, index: usize) -> bool {
+        if index == 0 || index >= self.points.len() {
+            return false;
+        }
+
+        if self.segment_starts[index] {
+            return true;
+        }
+
+        match self.max_gap {
+            Some(max_gap) => self.points[index].time - self.points[index - 1].time > max_gap,
+            None => false,
+        }
+    }
+
     // 指定された日付の位置まで移動する関数
     fn move_to_dt(&mut self, dt: DateTime<Utc>) -> bool {
         let f = |dt: DateTime<Utc>, point: &Option<TrackPoint>| -> bool {
@@ -116,9 +666,10 @@ impl<'a> TrackIter<'a> {
         }
 
         // 指定された日付までデータを探す
-        while let Some(point) = self.points.next() {
+        while self.index < self.points.len() {
             self.point_prev = self.point_next;
-            self.point_next = Some(point);
+            self.point_next = Some(self.points[self.index]);
+            self.index += 1;
 
             // 開始日付チェック
             if f(dt, &self.point_next) {
@@ -130,43 +681,57 @@ impl<'a> TrackIter<'a> {
     }
 
     // 位置計算
-    fn calc_position(prev: &TrackPoint, next: &TrackPoint, current: DateTime<Utc>) -> TrackPoint {
+    fn calc_position(prev: &TrackPoint, next: &TrackPoint, current: DateTime<Utc>) -> Result<TrackPoint> {
         let current_mills = current.timestamp_millis();
         let prev_mills = prev.time.timestamp_millis();
         let next_mills = next.time.timestamp_millis();
 
-        // current_millsが範囲内かチェック
-        if prev_mills > current_mills && next_mills < current_mills {
-            panic!("パラメータ範囲エラー");
-        }
-
         // prev, next が同一の場合、計算不要でprevを返す(先頭データのみ発生する)
         if prev_mills == next_mills {
-            return prev.clone();
+            return Ok(*prev);
+        }
+
+        // current_millsが範囲内かチェック
+        if current_mills < prev_mills || next_mills < current_mills {
+            return Err(anyhow::anyhow!(
+                "パラメータ範囲エラー: {} が {} 〜 {} の範囲外です",
+                current.to_rfc3339(),
+                prev.time.to_rfc3339(),
+                next.time.to_rfc3339()
+            ));
         }
 
         // 比率から lat, lng を計算
         let ratio =
             (current_mills as f64 - prev_mills as f64) / (next_mills as f64 - prev_mills as f64);
 
-        TrackPoint {
+        // 片方にしか値がない場合はある方の値を使います
+        let interpolate = |p: Option<f64>, n: Option<f64>| match (p, n) {
+            (Some(p), Some(n)) => Some(p + (n - p) * ratio),
+            (p, n) => p.or(n),
+        };
+
+        Ok(TrackPoint {
             lat: prev.lat + (next.lat - prev.lat) * ratio,
             lng: prev.lng + (next.lng - prev.lng) * ratio,
+            ele: interpolate(prev.ele, next.ele),
+            hr: interpolate(prev.hr, next.hr),
+            cad: interpolate(prev.cad, next.cad),
+            power: interpolate(prev.power, next.power),
+            temp: interpolate(prev.temp, next.temp),
             time: current,
-        }
+        })
     }
-}
 
-impl<'a> Iterator for TrackIter<'a> {
-    type Item = TrackPoint;
-
-    fn next(&mut self) -> Option<Self::Item> {
+    // 次のフレーム(終わりの場合は None)
+    fn next_frame(&mut self) -> Result<Option<Frame>> {
         // 初回かどうかの確認
         if self.current.is_none() {
             // 最初の日付を取ります
-            if let Some(tp) = self.points.next() {
+            if let Some(tp) = self.points.first().copied() {
                 self.point_prev = Some(tp);
                 self.point_next = Some(tp);
+                self.index = 1;
 
                 self.current = if self.start_dt.is_some() {
                     self.start_dt
@@ -174,43 +739,128 @@ impl<'a> Iterator for TrackIter<'a> {
                     Some(tp.time)
                 };
 
-                self.current_fps = 0;
+                self.elapsed = if self.is_by_distance() {
+                    self.distance_at_time(self.current.unwrap())
+                } else {
+                    0.0
+                };
             } else {
-                return None;
+                return Ok(None);
             }
         }
 
         // ターゲットの時間をmsec単位で取得する
-        let duration = Duration::milliseconds((self.current_fps * 100 / self.fps) as i64);
-        let current: DateTime<Utc> = self.current.unwrap() + duration;
+        let mut current: DateTime<Utc> = if self.is_by_distance() {
+            match self.time_at_distance(self.elapsed) {
+                Some(time) => time,
+                None => return Ok(None),
+            }
+        } else {
+            self.current.unwrap() + Duration::milliseconds(self.elapsed.round() as i64)
+        };
+
+        // 停止区間の処理
+        if let Some(stop) = self.stop_at(current) {
+            let card_frames = self.fps * STOP_CARD_SECONDS;
+            if self.stop_mode == StopMode::Card && self.card_frame < card_frames {
+                // 時間を進めずに停止時間を表示します
+                let progress = self.card_frame as f64 / card_frames as f64;
+                self.card_frame += 1;
+
+                return Ok(Some(Frame {
+                    point: TrackPoint {
+                        time: current,
+                        ..stop.point
+                    },
+                    event: Some(FrameEvent::Paused {
+                        duration: stop.duration(),
+                        progress,
+                    }),
+                }));
+            }
+
+            if let StopMode::Skip | StopMode::Card = self.stop_mode {
+                // 停止区間の終わりまで時間を進めます
+                self.card_frame = 0;
+                self.current = Some(stop.end);
+                self.elapsed = 0.0;
+                current = stop.end;
+            }
+        }
+
+        // データを探します
+        if !self.move_to_dt(current) {
+            return Ok(None);
+        }
+
+        let prev = self.point_prev.unwrap();
+        let next = self.point_next.unwrap();
+        let mut event = None;
+
+        // ギャップ(セグメントの切れ目、長時間の途絶)の処理
+        let in_gap = prev.time < next.time && self.is_gap_before(self.index - 1);
+        let track_point = match self.gap_mode {
+            GapMode::Skip if in_gap => {
+                // 補間せず、次のポイントまで時間を進めます
+                self.point_prev = self.point_next;
+                self.current = Some(next.time);
+                if !self.is_by_distance() {
+                    self.elapsed = 0.0;
+                }
+                current = next.time;
+
+                next
+            }
+            GapMode::Jump if in_gap && current < next.time => {
+                // 途絶した位置に留まります
+                let progress = (current - prev.time).num_milliseconds() as f64
+                    / (next.time - prev.time).num_milliseconds() as f64;
+                event = Some(FrameEvent::SignalLost { progress });
+
+                TrackPoint {
+                    time: current,
+                    ..prev
+                }
+            }
+            // 位置計算
+            _ if self.spline => {
+                let point = TrackIter::calc_position(&prev, &next, current)?;
+                self.spline_position(point, &prev, &next)
+            }
+            _ => TrackIter::calc_position(&prev, &next, current)?,
+        };
 
         // 終了時間過ぎているかチェック
         if let Some(dt) = self.end_dt {
             if dt < current {
-                return None
+                return Ok(None);
             }
         }
 
-        // データを探します
-        if self.move_to_dt(current) == false {
-            return None;
+        // 次のフレームへ(1フレームで speed / fps 秒、距離基準では speed / fps m 進めます)
+        let unit = if self.is_by_distance() { 1.0 } else { 1000.0 };
+        self.elapsed += self.speed * self.rate_at(current) * unit / self.fps as f64;
+
+        // 早送りは停止区間の終わりを越えて進めません
+        if let Some(stop) = self.stop_at(current) {
+            if self.stop_mode == StopMode::Fast {
+                let end = (stop.end - self.current.unwrap()).num_milliseconds() as f64;
+                self.elapsed = self.elapsed.min(end);
+            }
         }
 
-        // 位置計算
-        let track_point = TrackIter::calc_position(
-            &self.point_prev.unwrap(),
-            &self.point_next.unwrap(),
-            current,
-        );
+        Ok(Some(Frame {
+            point: track_point,
+            event,
+        }))
+    }
+}
 
-        // 次のデータへカウントアップ
-        self.current_fps = self.current_fps + 1;
-        if self.current_fps >= self.fps {
-            self.current = Some(self.current.unwrap() + Duration::seconds(1));
-            self.current_fps = 0;
-        }
+impl Iterator for TrackIter {
+    type Item = Result<Frame>;
 
-        Some(track_point)
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_frame().transpose()
     }
 }
 
@@ -225,7 +875,7 @@ fn hoge() {
     let reader = BufReader::new(f);
 
     let gpx = gpx::read(reader).unwrap();
-    let track = gpx.tracks.first().unwrap();
+    let track = TrackLog::from_gpx_track(gpx.tracks.first().unwrap(), &[]);
 
     // 2020-07-31T22:27:46.000Z
 
@@ -240,7 +890,7 @@ fn hoge() {
 
     let end_date: Option<DateTime<Utc>> = None;
 
-    let mut iter = TrackIter::get_iter(track, 60, start_date, end_date);
+    let mut iter = TrackIter::get_iter(&track, 60, start_date, end_date);
     let r = iter.move_to_dt(start_date.unwrap());
     let next = iter.point_next.clone().unwrap();
     let prev = iter.point_prev.clone().unwrap();
@@ -259,7 +909,7 @@ fn hoge() {
 
     println!("{:?} {:?}", prev2, next2);
 
-    let r = TrackIter::calc_position(&prev, &next, start_date.unwrap());
+    let r = TrackIter::calc_position(&prev, &next, start_date.unwrap()).unwrap();
     println!("{:?}", r);
 
     assert_eq!(next.lat, r.lat);
@@ -276,7 +926,7 @@ fn hage() {
     let reader = BufReader::new(f);
 
     let gpx = gpx::read(reader).unwrap();
-    let track = gpx.tracks.first().unwrap();
+    let track = TrackLog::from_gpx_track(gpx.tracks.first().unwrap(), &[]);
 
     let start_date: Option<DateTime<Utc>> = Utc::now()
         .with_year(2020)
@@ -297,8 +947,153 @@ fn hage() {
         .and_then(|t| t.with_second(46))
         .and_then(|t| t.with_nanosecond(0));
 
-    let iter = TrackIter::get_iter(track, 2, start_date, end_date);
+    let iter = TrackIter::get_iter(&track, 2, start_date, end_date);
     for track in iter {
         println!("{:?}", track);
     }
 }
+
+// テスト用の時刻(基準の時刻から sec 秒後)
+#[cfg(test)]
+pub fn test_time(sec: i64) -> DateTime<Utc> {
+    use chrono::TimeZone;
+
+    Utc.timestamp(1_596_234_400 + sec, 0)
+}
+
+// テスト用の、経度 139.5 の線上のポイント
+#[cfg(test)]
+pub fn test_point(sec: i64, lat: f64) -> TrackPoint {
+    TrackPoint::new(test_time(sec), lat, 139.5)
+}
+
+// テスト用の、一つのセグメントだけのトラック
+#[cfg(test)]
+pub fn test_track(points: Vec<TrackPoint>) -> TrackLog {
+    TrackLog {
+        name: None,
+        segments: vec![points],
+        waypoints: Vec::new(),
+    }
+}
+
+#[test]
+fn segment_gap() {
+    // 2つ目のセグメントは5分あとに始まる
+    let track = TrackLog {
+        name: None,
+        segments: vec![
+            vec![test_point(0, 35.0), test_point(2, 35.1)],
+            vec![test_point(300, 36.0), test_point(302, 36.1)],
+        ],
+        waypoints: Vec::new(),
+    };
+
+    let points: Vec<TrackPoint> = TrackIter::get_iter(&track, 2, None, None)
+        .map(|frame| frame.unwrap().point)
+        .collect();
+    assert_eq!(points.len(), 10);
+
+    // セグメントの間は補間されない
+    assert!(points.iter().all(|p| p.lat <= 35.1 || p.lat >= 36.0));
+    assert_eq!(points[5].time, track.segments[1][0].time);
+
+    // その場に留まる場合は、ギャップの間も時間が進み、途絶中の印が付く
+    let frames: Vec<Frame> = TrackIter::get_iter(&track, 2, None, None)
+        .with_gap(GapMode::Jump, None)
+        .collect::<Result<_>>()
+        .unwrap();
+    assert_eq!(frames.len(), 605);
+    assert_eq!(frames[5].point.lat, 35.1);
+    assert!(matches!(frames[5].event, Some(FrameEvent::SignalLost { .. })));
+    assert_eq!(frames[600].point.lat, 36.0);
+    assert_eq!(frames[600].event, None);
+
+    // セグメント内でも max_gap を超える間隔はギャップとみなす
+    let track = test_track(track.segments.concat());
+    let frames = TrackIter::get_iter(&track, 2, None, None).with_gap(GapMode::Skip, Some(Duration::seconds(60)));
+    assert_eq!(frames.count(), 10);
+}
+
+#[test]
+fn playback_speed() {
+    let track = test_track(vec![test_point(0, 35.0), test_point(600, 35.1)]);
+
+    // 実時間では1フレームで 1/fps 秒進む(2秒で60フレーム + 最後の1フレーム)
+    let frames: Vec<Frame> = TrackIter::get_iter(&track, 30, None, Some(test_time(2)))
+        .collect::<Result<_>>()
+        .unwrap();
+    assert_eq!(frames.len(), 61);
+    assert_eq!(frames[15].point.time, test_time(0) + Duration::milliseconds(500));
+
+    // 60倍速なら10分が10秒(20フレーム + 最後の1フレーム)
+    let frames: Vec<Frame> = TrackIter::get_iter(&track, 2, None, None)
+        .with_playback(Playback::Speed(60.0))
+        .collect::<Result<_>>()
+        .unwrap();
+    assert_eq!(frames.len(), 21);
+    assert_eq!(frames[1].point.time, test_time(30));
+
+    // 長さを指定した場合は全体が収まるように速度を決める
+    let iter = TrackIter::get_iter(&track, 2, None, Some(test_time(300)))
+        .with_playback(Playback::Duration(Duration::seconds(10)));
+    assert_eq!(iter.speed(), 30.0);
+    assert_eq!(iter.count(), 21);
+}
+
+#[test]
+fn distance_playback() {
+    // 北へ約1.1km進むのに、前半は500秒、後半は100秒かかる
+    let track = test_track(vec![test_point(0, 35.0), test_point(500, 35.005), test_point(600, 35.01)]);
+    let half = distance(&track.segments[0][0], &track.segments[0][1]);
+    assert!((half - 556.0).abs() < 1.0, "{}", half);
+
+    // 1フレームで同じ距離だけ進むので、前半と後半が同じフレーム数になる
+    let frames: Vec<Frame> = TrackIter::get_iter(&track, 2, None, None)
+        .with_playback(Playback::DistanceDuration(Duration::seconds(10)))
+        .collect::<Result<_>>()
+        .unwrap();
+    assert_eq!(frames.len(), 21);
+    assert_eq!(frames[10].point.time, test_time(500));
+    assert!((frames[5].point.lat - 35.0025).abs() < 1e-6);
+}
+
+#[test]
+fn time_order() {
+    let track = test_track(vec![test_point(0, 35.0), test_point(2, 35.2), test_point(1, 35.1), test_point(3, 35.3)]);
+
+    // 範囲外の時刻はパニックせずにエラーになる
+    let (prev, next) = (&track.segments[0][0], &track.segments[0][1]);
+    assert!(TrackIter::calc_position(prev, next, test_time(3)).is_err());
+
+    let ordered = |order| -> Result<TrackLog> {
+        let mut track = track.clone();
+        track.order_by_time(order)?;
+        Ok(track)
+    };
+    assert!(ordered(TimeOrder::Error).is_err());
+
+    let lats = |order| -> Vec<f64> {
+        TrackIter::get_iter(&ordered(order).unwrap(), 1, None, None)
+            .map(|frame| frame.unwrap().point.lat)
+            .collect()
+    };
+    assert_eq!(lats(TimeOrder::Sort), vec![35.0, 35.1, 35.2, 35.3]);
+    assert_eq!(lats(TimeOrder::Drop), vec![35.0, 35.1, 35.2, 35.3]);
+    assert_eq!(ordered(TimeOrder::Drop).unwrap().segments[0].len(), 3);
+
+    // 時間が重なっているセグメントはまとめ、重なっていないセグメントはそのまま
+    let mut track = TrackLog {
+        segments: vec![
+            vec![test_point(10, 36.0), test_point(12, 36.2)],
+            vec![test_point(0, 35.0), test_point(2, 35.2), test_point(4, 35.4)],
+            vec![test_point(1, 35.1), test_point(3, 35.3)],
+        ],
+        ..Default::default()
+    };
+    track.order_by_time(TimeOrder::Sort).unwrap();
+    assert_eq!(track.segments.len(), 2);
+    let times: Vec<DateTime<Utc>> = track.segments.iter().flatten().map(|point| point.time).collect();
+    assert!(times.windows(2).all(|pair| pair[0] <= pair[1]));
+    assert_eq!(track.segments[0].len(), 5);
+}
@@ -0,0 +1,309 @@
+// 距離、ウェイポイント、座標への最接近、範囲(矩形・GeoJSONのポリゴン)による処理対象の切り出し
+use crate::track_point::{self, TrackLog, TrackPoint};
+use anyhow::Result;
+use chrono::{DateTime, Duration, Utc};
+use serde_json::Value;
+use std::fs;
+
+// 処理対象の開始・終了日時
+pub type TimeRange = (Option<DateTime<Utc>>, Option<DateTime<Utc>>);
+
+// 経度・緯度の順の座標の列(GeoJSONと同じ順)
+pub type Ring = Vec<(f64, f64)>;
+
+// 切り出しの開始・終了の位置
+#[derive(Debug, Clone, PartialEq)]
+pub enum TrimBound {
+    // トラックの開始からの距離(m)
+    Distance(f64),
+    // この名前のウェイポイントに最も近づいた地点
+    Waypoint(String),
+    // この座標(緯度, 経度)に最も近づいた地点
+    Nearest(f64, f64),
+}
+
+// 範囲
+#[derive(Debug, Clone, PartialEq)]
+pub enum Area {
+    BoundingBox { south: f64, west: f64, north: f64, east: f64 },
+    // ポリゴンごとに外周と穴の座標の列
+    Polygons(Vec<Vec<Ring>>),
+}
+
+impl Area {
+    // "南端の緯度,西端の経度,北端の緯度,東端の経度" または GeoJSON ファイルのパス
+    pub fn parse(value: &str) -> Result<Self> {
+        let lower = value.to_lowercase();
+        if lower.ends_with(".geojson") || lower.ends_with(".json") {
+            return Self::from_geojson(&fs::read_to_string(value)?);
+        }
+
+        let values = value
+            .split(',')
+            .map(|x| x.trim().parse::<f64>())
+            .collect::<Result<Vec<f64>, _>>()
+            .ok()
+            .filter(|values| values.len() == 4 && values[0] < values[2] && values[1] < values[3])
+            .ok_or_else(|| {
+                anyhow::anyhow!("範囲 \"{}\" は 南端の緯度,西端の経度,北端の緯度,東端の経度 で指定してください", value)
+            })?;
+
+        Ok(Area::BoundingBox {
+            south: values[0],
+            west: values[1],
+            north: values[2],
+            east: values[3],
+        })
+    }
+
+    // GeoJSON のポリゴンとマルチポリゴンを読み込みます(点や線は無視します)
+    pub fn from_geojson(text: &str) -> Result<Self> {
+        let value: Value = serde_json::from_str(text)?;
+        let mut polygons = Vec::new();
+        read_polygons(&value, &mut polygons)?;
+
+        if polygons.is_empty() {
+            return Err(anyhow::anyhow!("GeoJSON にポリゴンがありません"));
+        }
+        Ok(Area::Polygons(polygons))
+    }
+
+    pub fn contains(&self, point: &TrackPoint) -> bool {
+        match self {
+            Area::BoundingBox { south, west, north, east } => {
+                (*south..=*north).contains(&point.lat) && (*west..=*east).contains(&point.lng)
+            }
+            Area::Polygons(polygons) => polygons.iter().any(|rings| polygon_contains(rings, point.lng, point.lat)),
+        }
+    }
+
+    // ポリゴンごとの外周と穴(矩形は4点の外周のみ)
+    pub fn polygons(&self) -> Vec<Vec<Ring>> {
+        match self {
+            Area::BoundingBox { south, west, north, east } => {
+                vec![vec![vec![(*west, *south), (*east, *south), (*east, *north), (*west, *north)]]]
+            }
+            Area::Polygons(polygons) => polygons.clone(),
+        }
+    }
+}
+
+// 外周の内側で、穴の外側にあるかどうか
+pub fn polygon_contains(rings: &[Ring], x: f64, y: f64) -> bool {
+    let (outer, holes) = rings.split_first().unwrap();
+    ring_contains(outer, x, y) && !holes.iter().any(|hole| ring_contains(hole, x, y))
+}
+
+fn read_polygons(value: &Value, polygons: &mut Vec<Vec<Ring>>) -> Result<()> {
+    let children = |key: &str| value[key].as_array().cloned().unwrap_or_default();
+
+    match value["type"].as_str() {
+        Some("FeatureCollection") => {
+            for feature in children("features") {
+                read_polygons(&feature, polygons)?;
+            }
+        }
+        Some("GeometryCollection") => {
+            for geometry in children("geometries") {
+                read_polygons(&geometry, polygons)?;
+            }
+        }
+        Some("Feature") => read_polygons(&value["geometry"], polygons)?,
+        Some("Polygon") => polygons.push(read_rings(&value["coordinates"])?),
+        Some("MultiPolygon") => {
+            for polygon in children("coordinates") {
+                polygons.push(read_rings(&polygon)?);
+            }
+        }
+        _ => {}
+    }
+
+    Ok(())
+}
+
+fn read_rings(value: &Value) -> Result<Vec<Ring>> {
+    let error = || anyhow::anyhow!("GeoJSON のポリゴンの座標が正しくありません");
+
+    let rings = value
+        .as_array()
+        .ok_or_else(error)?
+        .iter()
+        .map(|ring| {
+            ring.as_array()?
+                .iter()
+                .map(|position| Some((position.get(0)?.as_f64()?, position.get(1)?.as_f64()?)))
+                .collect::<Option<Ring>>()
+        })
+        .collect::<Option<Vec<Ring>>>()
+        .ok_or_else(error)?;
+
+    if rings.is_empty() || rings.iter().any(|ring| ring.len() < 3) {
+        return Err(error());
+    }
+    Ok(rings)
+}
+
+// 点(x, y)が多角形の内側にあるかどうか(レイキャスティング)
+pub fn ring_contains(ring: &[(f64, f64)], x: f64, y: f64) -> bool {
+    let mut inside = false;
+
+    for (index, &(x1, y1)) in ring.iter().enumerate() {
+        let (x2, y2) = ring[(index + 1) % ring.len()];
+        if (y1 > y) != (y2 > y) && x < x1 + (y - y1) / (y2 - y1) * (x2 - x1) {
+            inside = !inside;
+        }
+    }
+
+    inside
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Trim {
+    pub from: Option<TrimBound>,
+    pub to: Option<TrimBound>,
+    // この範囲に最初に入ってから最後に出るまで
+    pub area: Option<Area>,
+}
+
+impl Trim {
+    // 切り出す開始・終了日時
+    pub fn time_range(&self, track: &TrackLog) -> Result<TimeRange> {
+        // トラックの開始からの距離(セグメントの間の移動は含めません)
+        let mut points: Vec<(&TrackPoint, f64)> = Vec::new();
+        let mut total = 0.0;
+        for segment in &track.segments {
+            for (index, point) in segment.iter().enumerate() {
+                if index > 0 {
+                    total += track_point::distance(&segment[index - 1], point);
+                }
+                points.push((point, total));
+            }
+        }
+
+        let mut start = match &self.from {
+            Some(bound) => Some(time_at(track, &points, bound)?),
+            None => None,
+        };
+        let mut end = match &self.to {
+            Some(bound @ TrimBound::Distance(_)) => Some(time_at(track, &points, bound)?),
+            // 折り返しのあるコースでは、開始より後で最も近づいた地点にします
+            Some(bound) => {
+                let after = start.map_or(0, |start| points.partition_point(|(point, _)| point.time < start));
+                Some(time_at(track, &points[after..], bound)?)
+            }
+            None => None,
+        };
+
+        if let Some(area) = &self.area {
+            let mut inside = points.iter().filter(|(point, _)| area.contains(point));
+            let first = inside
+                .next()
+                .ok_or_else(|| anyhow::anyhow!("トラックが指定した範囲に入っていません"))?;
+            let last = inside.next_back().unwrap_or(first);
+
+            start = start.max(Some(first.0.time));
+            end = Some(end.map_or(last.0.time, |end| end.min(last.0.time)));
+        }
+
+        Ok((start, end))
+    }
+}
+
+fn time_at(track: &TrackLog, points: &[(&TrackPoint, f64)], bound: &TrimBound) -> Result<DateTime<Utc>> {
+    if points.is_empty() {
+        return Err(anyhow::anyhow!("データがみつかりません"));
+    }
+
+    match bound {
+        TrimBound::Distance(distance) => {
+            let total = points[points.len() - 1].1;
+            if *distance < 0.0 || *distance > total {
+                return Err(anyhow::anyhow!(
+                    "距離 {:.1}km はトラックの距離 {:.1}km を超えています",
+                    distance / 1000.0,
+                    total / 1000.0
+                ));
+            }
+
+            // 前後のポイントの間は補間します
+            let index = points.partition_point(|(_, d)| d < distance);
+            if index == 0 {
+                return Ok(points[0].0.time);
+            }
+            let ((prev, prev_distance), (next, next_distance)) = (points[index - 1], points[index]);
+            let ratio = if next_distance > prev_distance {
+                (distance - prev_distance) / (next_distance - prev_distance)
+            } else {
+                0.0
+            };
+            let millis = ((next.time - prev.time).num_milliseconds() as f64 * ratio).round() as i64;
+            Ok(prev.time + Duration::milliseconds(millis))
+        }
+        TrimBound::Waypoint(name) => {
+            let waypoint = track
+                .waypoints
+                .iter()
+                .find(|waypoint| &waypoint.name == name)
+                .ok_or_else(|| anyhow::anyhow!("ウェイポイント \"{}\" がみつかりません", name))?;
+            time_at(track, points, &TrimBound::Nearest(waypoint.lat, waypoint.lng))
+        }
+        TrimBound::Nearest(lat, lng) => {
+            let target = TrackPoint::new(points[0].0.time, *lat, *lng);
+            let distances = points.iter().map(|(point, _)| (point.time, track_point::distance(point, &target)));
+            let (time, _) = distances.fold((points[0].0.time, f64::MAX), |nearest, x| {
+                if x.1 < nearest.1 {
+                    x
+                } else {
+                    nearest
+                }
+            });
+            Ok(time)
+        }
+    }
+}
+
+#[test]
+fn trim_track() {
+    use crate::track_point::{test_point, test_time, test_track, Waypoint};
+
+    // 1秒ごとに約10m北へ進み、5km地点で折り返して戻る
+    let step = 10.0 / 111_195.0;
+    let mut points: Vec<TrackPoint> = (0..=500).map(|i| test_point(i, 35.0 + i as f64 * step)).collect();
+    points.extend((1..=500).map(|i| test_point(500 + i, 35.0 + (500 - i) as f64 * step)));
+    let track = TrackLog {
+        waypoints: vec![Waypoint {
+            name: "峠".to_string(),
+            lat: 35.0 + 300.0 * step,
+            lng: 139.501,
+        }],
+        ..test_track(points)
+    };
+
+    let trim = Trim {
+        from: Some(TrimBound::Distance(1200.0)),
+        to: Some(TrimBound::Distance(2500.0)),
+        area: None,
+    };
+    assert_eq!(trim.time_range(&track).unwrap(), (Some(test_time(120)), Some(test_time(250))));
+    assert!(Trim { from: Some(TrimBound::Distance(20_000.0)), ..Default::default() }.time_range(&track).is_err());
+
+    // 帰りに通る地点は開始より後を探します
+    let trim = Trim {
+        from: Some(TrimBound::Waypoint("峠".to_string())),
+        to: Some(TrimBound::Nearest(35.0 + 100.0 * step, 139.5)),
+        area: None,
+    };
+    assert_eq!(trim.time_range(&track).unwrap(), (Some(test_time(300)), Some(test_time(900))));
+
+    // 範囲に入ってから出るまで
+    let area = Area::parse(&format!("{},139.4,{},139.6", 35.0 + 100.0 * step, 35.0 + 200.0 * step)).unwrap();
+    let trim = Trim { area: Some(area), ..Default::default() };
+    assert_eq!(trim.time_range(&track).unwrap(), (Some(test_time(100)), Some(test_time(900))));
+
+    let geojson = r#"{"type": "FeatureCollection", "features": [{"type": "Feature", "properties": {},
+        "geometry": {"type": "Polygon", "coordinates": [[[139.4, 35.04], [139.6, 35.04], [139.6, 35.1], [139.4, 35.1], [139.4, 35.04]]]}}]}"#;
+    let trim = Trim { area: Some(Area::from_geojson(geojson).unwrap()), ..Default::default() };
+    let (start, end) = trim.time_range(&track).unwrap();
+    assert!(start.unwrap() > test_time(440) && end.unwrap() < test_time(560));
+    assert!(Area::parse("35.1,139.6,35.0,139.4").is_err());
+}